@@ -0,0 +1,219 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use ipnet::IpNet;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Rules deciding which addresses may connect.
+#[derive(Debug, Default, Clone)]
+pub struct AccessRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessRules {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        AccessRules { allow, deny }
+    }
+
+    /// Load rules from a file. Each line has the form `allow <cidr>` or `deny <cidr>`, empty lines
+    /// and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut rules = AccessRules::default();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), idx + 1, msg));
+
+            let mut parts = line.split_whitespace();
+            let list = match parts.next() {
+                Some("allow") => &mut rules.allow,
+                Some("deny") => &mut rules.deny,
+                _ => return Err(err("expected 'allow' or 'deny'".to_owned())),
+            };
+
+            match (parts.next(), parts.next()) {
+                (Some(net), None) => list.push(parse_net(net).map_err(err)?),
+                _ => return Err(err("expected a single address or network".to_owned())),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    /// Combine these rules with another set of rules.
+    pub fn extend(&mut self, other: &AccessRules) {
+        self.allow.extend_from_slice(&other.allow);
+        self.deny.extend_from_slice(&other.deny);
+    }
+
+    /// Check whether a client with the given address may connect. Denied networks take priority
+    /// over allowed ones, and if no networks are explicitly allowed, every address not denied is.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            v4 => v4,
+        };
+
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Parse a network in CIDR notation, or a single address.
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+    IpNet::from_str(s)
+        .or_else(|_| IpAddr::from_str(s).map(IpNet::from))
+        .map_err(|_| format!("invalid address or network '{}'", s))
+}
+
+/// Access rules given on the command line and from an optional file, which is reloaded when
+/// receiving SIGHUP.
+pub struct AccessControl {
+    fixed: AccessRules,
+    file: Option<PathBuf>,
+    current: RwLock<AccessRules>,
+}
+
+impl AccessControl {
+    pub fn new(fixed: AccessRules, file: Option<PathBuf>) -> io::Result<Arc<Self>> {
+        let ac = AccessControl {
+            current: RwLock::new(fixed.clone()),
+            fixed,
+            file,
+        };
+
+        ac.reload()?;
+
+        Ok(Arc::new(ac))
+    }
+
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        self.current.read().unwrap().permits(addr)
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let mut rules = self.fixed.clone();
+
+        if let Some(path) = &self.file {
+            rules.extend(&AccessRules::load(path)?);
+        }
+
+        *self.current.write().unwrap() = rules;
+
+        Ok(())
+    }
+
+    /// Start a task reloading the rules file whenever SIGHUP is received.
+    pub fn reload_on_hangup(self: &Arc<Self>) -> io::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let this = self.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match this.reload() {
+                    Ok(_) => eprintln!("reloaded access rules"),
+                    Err(e) => eprintln!("failed to reload access rules, keeping the previous ones: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|n| parse_net(n).unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_nets() {
+        assert_eq!(parse_net("10.0.0.0/8"), Ok("10.0.0.0/8".parse().unwrap()));
+        assert_eq!(parse_net("10.1.2.3"), Ok("10.1.2.3/32".parse().unwrap()));
+        assert_eq!(parse_net("::1"), Ok("::1/128".parse().unwrap()));
+        assert!(parse_net("10.0.0.0/33").is_err());
+        assert!(parse_net("localhost").is_err());
+    }
+
+    #[test]
+    fn permits_everything_by_default() {
+        let rules = AccessRules::default();
+        assert!(rules.permits(ip("192.0.2.1")));
+        assert!(rules.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn deny_takes_priority() {
+        let rules = AccessRules::new(nets(&["10.0.0.0/8"]), nets(&["10.1.0.0/16"]));
+        assert!(rules.permits(ip("10.2.0.1")));
+        assert!(!rules.permits(ip("10.1.0.1")));
+        assert!(!rules.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn deny_only() {
+        let rules = AccessRules::new(vec![], nets(&["192.0.2.0/24"]));
+        assert!(!rules.permits(ip("192.0.2.1")));
+        assert!(rules.permits(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        let rules = AccessRules::new(nets(&["127.0.0.1"]), vec![]);
+        assert!(rules.permits(ip("::ffff:127.0.0.1")));
+        assert!(!rules.permits(ip("::ffff:127.0.0.2")));
+    }
+
+    #[test]
+    fn extend() {
+        let mut rules = AccessRules::new(nets(&["10.0.0.0/8"]), vec![]);
+        rules.extend(&AccessRules::new(vec![], nets(&["10.0.0.1"])));
+        assert!(rules.permits(ip("10.0.0.2")));
+        assert!(!rules.permits(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("conlink-access-test-{}", std::process::id()));
+
+        fs::write(&path, "# office\nallow 10.0.0.0/8\n\n  deny 10.0.0.1  \n").unwrap();
+        let rules = AccessRules::load(&path).unwrap();
+        assert!(rules.permits(ip("10.0.0.2")));
+        assert!(!rules.permits(ip("10.0.0.1")));
+        assert!(!rules.permits(ip("192.0.2.1")));
+
+        fs::write(&path, "allow 10.0.0.0/8\npermit 10.0.0.1\n").unwrap();
+        let err = AccessRules::load(&path).unwrap_err();
+        assert!(err.to_string().ends_with(":2: expected 'allow' or 'deny'"), "{}", err);
+
+        fs::write(&path, "allow 10.0.0.0/8 10.0.0.1\n").unwrap();
+        assert!(AccessRules::load(&path).is_err());
+
+        let _ = fs::remove_file(&path);
+    }
+}
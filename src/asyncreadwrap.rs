@@ -1,38 +1,48 @@
 use std::io;
-use std::io::ErrorKind;
 
 use futures::task::Context;
 use tokio::macros::support::{Pin, Poll};
-use tokio::prelude::AsyncRead;
-use tokio::stream::Stream;
+use tokio::prelude::{AsyncRead, AsyncWrite};
 
-pub struct StreamWrapper<T>
-    where T: AsyncRead + Unpin {
+/// A stream which returns some already read data before continuing to read from the inner
+/// stream.
+pub struct Prefixed<T> {
+    prefix: Vec<u8>,
+    pos: usize,
     inner: T,
 }
 
-impl<T> StreamWrapper<T>
-    where T: AsyncRead + Unpin {
-    pub fn of(inner: T) -> Self {
-        StreamWrapper { inner }
+impl<T> Prefixed<T> {
+    pub fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Prefixed { prefix, pos: 0, inner }
     }
-
-    pub fn into_inner(self) -> T { self.inner }
 }
 
-impl<T> Stream for StreamWrapper<T>
+impl<T> AsyncRead for Prefixed<T>
     where T: AsyncRead + Unpin {
-    type Item = io::Result<Vec<u8>>;
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.pos < self.prefix.len() {
+            let len = buf.len().min(self.prefix.len() - self.pos);
+            buf[..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(len));
+        }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut buf = Vec::new();
-        let f: io::Result<usize> = futures::ready!(Pin::new(&mut self.inner).poll_read_buf(cx, &mut buf));
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
 
-        match f {
-            Ok(_size) => Poll::Ready(Some(Ok(buf))),
-            Err(e)
-            if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset => Poll::Ready(None),
-            Err(e) => Poll::Ready(Some(Err(e))),
-        }
+impl<T> AsyncWrite for Prefixed<T>
+    where T: AsyncWrite + Unpin {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::stream::StreamExt;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::client::{ClientRef, Role, Transport};
+use crate::client::ws::parse_resize;
+
+const MAX_SECRET_LENGTH: usize = 1024;
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Checks the secrets clients send when connecting.
+pub struct Authenticator {
+    password: Option<String>,
+    /// Maps tokens to the name of their owner and the role they grant.
+    tokens: HashMap<String, (Arc<str>, Role)>,
+}
+
+/// What a client has authenticated with.
+#[derive(Debug, Clone)]
+pub enum Credential {
+    /// The shared password.
+    Password,
+
+    /// A named token from a token file.
+    Token(Arc<str>, Role),
+}
+
+impl Credential {
+    pub fn role(&self) -> Role {
+        match self {
+            Credential::Password => Role::Operator,
+            Credential::Token(_, role) => *role,
+        }
+    }
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Authenticator {
+            password: None,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Accept the password contained in the first line of the file.
+    pub fn load_password(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        let password = content.lines().next().unwrap_or("");
+
+        if password.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: password is empty", path.display())));
+        }
+
+        self.password = Some(password.to_owned());
+        Ok(())
+    }
+
+    /// Accept the tokens in the file, granting the given role. Each line has the form
+    /// `<name>:<token>`, empty lines and lines starting with `#` are ignored.
+    pub fn load_tokens(&mut self, path: &Path, role: Role) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.find(':') {
+                Some(pos) if pos > 0 && pos < line.len() - 1 => {
+                    self.tokens.insert(line[pos + 1..].to_owned(), (line[..pos].into(), role));
+                }
+                _ => {
+                    let msg = format!("{}:{}: expected <name>:<token>", path.display(), idx + 1);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn verify(&self, secret: &str) -> Option<Credential> {
+        // check every token so that timing doesn't reveal which one matched
+        let mut result = None;
+
+        for (token, (name, role)) in self.tokens.iter() {
+            if constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+                result = Some(Credential::Token(name.clone(), *role));
+            }
+        }
+
+        if let Some(password) = &self.password {
+            if constant_time_eq(password.as_bytes(), secret.as_bytes()) {
+                result = result.or(Some(Credential::Password));
+            }
+        }
+
+        result
+    }
+
+    fn prompt(&self) -> &'static str {
+        if self.password.is_some() { "password: " } else { "token: " }
+    }
+
+    /// Ask the client for its secret and check it. Returns `None` and closes the connection if
+    /// authentication fails.
+    pub async fn authenticate(&self, transport: &mut Transport, peer: ClientRef) -> io::Result<Option<Credential>> {
+        let secret = match tokio::time::timeout(AUTH_TIMEOUT, self.read_secret(transport)).await {
+            Ok(r) => r?,
+            Err(_) => {
+                eprintln!("authentication timed out for {}", peer);
+                return Ok(None);
+            }
+        };
+
+        let credential = self.verify(&secret);
+
+        match &credential {
+            Some(Credential::Token(name, _)) => eprintln!("{} authenticated as {}", peer, name),
+            Some(Credential::Password) => {}
+            None => {
+                eprintln!("authentication failed for {}", peer);
+                transport.send_text("authentication failed\n").await?;
+                transport.close().await?;
+            }
+        }
+
+        Ok(credential)
+    }
+
+    async fn read_secret(&self, transport: &mut Transport) -> io::Result<String> {
+        transport.send_text(self.prompt()).await?;
+
+        let mut buf = Vec::new();
+
+        match transport {
+            Transport::Raw(stream) => read_line(stream, &mut buf, b"\n").await?,
+            // a telnet client in character mode ends the line with CR NUL, which is decoded to a
+            // carriage return on its own
+            Transport::Telnet(stream) => read_line(stream, &mut buf, b"\r\n").await?,
+            Transport::WebSocket(ws) => loop {
+                let line = match ws.next().await {
+                    // the web terminal may report its size before it is asked for the secret
+                    Some(Ok(Message::Text(text))) if parse_resize(text.as_bytes()).is_some() => false,
+                    Some(Ok(Message::Binary(data))) if parse_resize(&data).is_some() => false,
+                    // a text mode client sends each line as a message of its own
+                    Some(Ok(Message::Text(text))) => {
+                        buf.extend_from_slice(text.as_bytes());
+                        true
+                    }
+                    // a binary mode client may send the secret one key at a time
+                    Some(Ok(Message::Binary(data))) => {
+                        buf.extend_from_slice(&data);
+                        buf.iter().any(|&b| b == b'\r' || b == b'\n')
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Some(Ok(_)) => false,
+                    Some(Err(e)) => return Err(io::Error::other(e)),
+                };
+
+                check_length(&buf)?;
+
+                if line {
+                    if let Some(pos) = buf.iter().position(|&b| b == b'\r' || b == b'\n') {
+                        buf.truncate(pos);
+                    }
+
+                    break;
+                }
+            },
+        }
+
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Read a line ending with any of the given bytes.
+async fn read_line(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>, ends: &[u8]) -> io::Result<()> {
+    // read byte by byte so that nothing after the line is consumed
+    loop {
+        let b = stream.read_u8().await?;
+
+        if ends.contains(&b) {
+            return Ok(());
+        }
+
+        buf.push(b);
+        check_length(buf)?;
+    }
+}
+
+fn check_length(buf: &[u8]) -> io::Result<()> {
+    if buf.len() > MAX_SECRET_LENGTH {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "secret too long"))
+    } else {
+        Ok(())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
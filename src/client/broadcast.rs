@@ -0,0 +1,589 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use tokio::stream::Stream;
+use tokio::sync::Notify;
+
+use crate::client::ClientRef;
+use crate::client::queue::{Output, QueueConfig, SlowClientPolicy};
+use crate::client::scrollback::{Scrollback, ScrollbackLimit};
+
+/// Which clients a published entry is for.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Audience {
+    All,
+    Only(ClientRef),
+    Except(ClientRef),
+}
+
+impl Audience {
+    fn includes(self, client: ClientRef) -> bool {
+        match self {
+            Audience::All => true,
+            Audience::Only(c) => c == client,
+            Audience::Except(c) => c != client,
+        }
+    }
+}
+
+/// Which of the program's output streams an entry came from.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Source {
+    Stdout,
+    Stderr,
+}
+
+/// The program's output streams a subscriber receives.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Streams {
+    pub stdout: bool,
+    pub stderr: bool,
+}
+
+impl Streams {
+    pub const ALL: Streams = Streams { stdout: true, stderr: true };
+
+    pub fn includes(self, source: Source) -> bool {
+        match source {
+            Source::Stdout => self.stdout,
+            Source::Stderr => self.stderr,
+        }
+    }
+}
+
+/// Output shared between all clients. Every entry is stored once and handed out by reference
+/// count, and each subscriber reads at its own position, so publishing doesn't get slower with
+/// more clients connected and clients don't contend with each other when reading.
+pub struct Broadcast<T> {
+    ring: RwLock<Ring<T>>,
+    /// Notified when a subscriber reads, for publishers waiting for blocking subscribers.
+    space: Notify,
+    waiting: AtomicUsize,
+    /// Creates the marker sent in place of output a subscriber skipped, from the number of
+    /// messages and bytes skipped.
+    skipped: Box<dyn Fn(usize, usize) -> T + Send + Sync>,
+}
+
+struct Ring<T> {
+    entries: VecDeque<Entry<T>>,
+    /// The sequence number of the first entry.
+    first: u64,
+    /// The number of bytes published so far.
+    offset: u64,
+    subscribers: Vec<Arc<Cursor>>,
+    scrollback: Scrollback<T>,
+    /// Set when nothing more is going to be published.
+    closed: bool,
+}
+
+struct Entry<T> {
+    data: Arc<T>,
+    audience: Audience,
+    /// The output stream this entry came from, or `None` for messages from conlink itself.
+    source: Option<Source>,
+    /// The number of bytes published before this entry.
+    offset: u64,
+}
+
+/// A subscriber's position in the output.
+struct Cursor {
+    client: ClientRef,
+    config: QueueConfig,
+    streams: Streams,
+    /// The sequence number of the next entry to read.
+    pos: AtomicU64,
+    /// The byte offset of the next entry to read.
+    offset: AtomicU64,
+    overflowed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Receives the output published after subscribing.
+pub struct Subscriber<T> {
+    broadcast: Arc<Broadcast<T>>,
+    cursor: Arc<Cursor>,
+}
+
+impl<T: Output> Broadcast<T> {
+    pub fn new(scrollback: ScrollbackLimit, skipped: impl Fn(usize, usize) -> T + Send + Sync + 'static) -> Arc<Self> {
+        Arc::new(Broadcast {
+            ring: RwLock::new(Ring {
+                entries: VecDeque::new(),
+                first: 0,
+                offset: 0,
+                subscribers: Vec::new(),
+                scrollback: Scrollback::new(scrollback),
+                closed: false,
+            }),
+            space: Notify::new(),
+            waiting: AtomicUsize::new(0),
+            skipped: Box::new(skipped),
+        })
+    }
+
+    /// Start receiving output for a client. Also returns the scrollback, which is exactly the
+    /// output published before the subscriber starts.
+    pub fn subscribe(self: &Arc<Self>, client: ClientRef, config: QueueConfig, streams: Streams) -> (Vec<Arc<T>>, Subscriber<T>) {
+        let mut ring = self.ring.write().unwrap();
+
+        let cursor = Arc::new(Cursor {
+            client,
+            config,
+            streams,
+            pos: AtomicU64::new(ring.head()),
+            offset: AtomicU64::new(ring.offset),
+            overflowed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+
+        ring.subscribers.push(cursor.clone());
+
+        (ring.scrollback.snapshot(streams), Subscriber { broadcast: self.clone(), cursor })
+    }
+
+    /// Send program output to all clients receiving the stream it came from, keeping it for the
+    /// scrollback.
+    pub async fn publish_output(&self, data: T, source: Source) {
+        self.publish(data, Audience::All, Some(source)).await
+    }
+
+    /// Send a message to some clients. Messages from conlink itself never wait for clients to
+    /// catch up, since they are often sent on behalf of a client, which then wouldn't get to read
+    /// its own output. They are kept for blocking clients even beyond their limits.
+    pub async fn send(&self, data: T, audience: Audience) {
+        self.publish(data, audience, None).await
+    }
+
+    async fn publish(&self, data: T, audience: Audience, source: Option<Source>) {
+        let data = Arc::new(data);
+        let size = data.size() as u64;
+
+        loop {
+            {
+                let mut ring = self.ring.write().unwrap();
+
+                if !ring.subscribers.iter().any(|c| ring.must_wait(c, size, audience, source)) {
+                    if let Some(source) = source {
+                        ring.scrollback.push(source, data.clone());
+                    }
+
+                    ring.push(data, size, audience, source);
+                    break;
+                }
+
+                self.waiting.fetch_add(1, Ordering::SeqCst);
+            }
+
+            self.space.notified().await;
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+
+
+        // let the next waiting publisher check again as well
+        if self.waiting.load(Ordering::SeqCst) > 0 {
+            self.space.notify();
+        }
+    }
+
+    /// Stop publishing, ending every subscriber once it has read what was published so far.
+    pub fn close(&self) {
+        let mut ring = self.ring.write().unwrap();
+        ring.closed = true;
+
+        for cursor in ring.subscribers.iter() {
+            cursor.waker.wake();
+        }
+    }
+
+    fn read_done(&self) {
+        if self.waiting.load(Ordering::SeqCst) > 0 {
+            self.space.notify();
+        }
+    }
+}
+
+impl<T> Ring<T> {
+    /// The sequence number of the next entry to be published.
+    fn head(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+
+    fn get(&self, pos: u64) -> &Entry<T> {
+        &self.entries[(pos - self.first) as usize]
+    }
+
+    /// Check whether a subscriber has more unread output than its limits allow. A single entry is
+    /// always allowed, no matter its size. Blocking subscribers only fall behind if entries they
+    /// haven't read are gone, since entries published without waiting for them are kept.
+    fn exceeds(&self, cursor: &Cursor, pos: u64, offset: u64) -> bool {
+        let count = self.head() - pos;
+        let bytes = self.offset - offset;
+
+        if pos < self.first {
+            return true;
+        }
+
+        if cursor.config.policy == SlowClientPolicy::Block {
+            return false;
+        }
+
+        count > 1 && (count > cursor.config.max_messages as u64 || bytes > cursor.config.max_bytes as u64)
+    }
+
+    /// Check whether publishing an entry has to wait for a subscriber to catch up. Only program
+    /// output waits, and only for blocking subscribers that receive it.
+    fn must_wait(&self, cursor: &Cursor, size: u64, audience: Audience, source: Option<Source>) -> bool {
+        let source = match source {
+            Some(source) => source,
+            None => return false,
+        };
+
+        cursor.config.policy == SlowClientPolicy::Block
+            && audience.includes(cursor.client)
+            && cursor.streams.includes(source)
+            && self.is_full(cursor, size)
+    }
+
+    /// Check whether publishing an entry of the given size would exceed a subscriber's limits.
+    fn is_full(&self, cursor: &Cursor, size: u64) -> bool {
+        let pos = cursor.pos.load(Ordering::SeqCst);
+        let offset = cursor.offset.load(Ordering::SeqCst);
+        let count = self.head() - pos;
+        let bytes = self.offset - offset;
+
+        !cursor.overflowed.load(Ordering::SeqCst) && count > 0 &&
+            (count + 1 > cursor.config.max_messages as u64 || bytes + size > cursor.config.max_bytes as u64)
+    }
+
+    fn push(&mut self, data: Arc<T>, size: u64, audience: Audience, source: Option<Source>) {
+        self.entries.push_back(Entry { data, audience, source, offset: self.offset });
+        self.offset += size;
+
+        for cursor in self.subscribers.iter() {
+            if cursor.config.policy == SlowClientPolicy::Disconnect {
+                let pos = cursor.pos.load(Ordering::SeqCst);
+                let offset = cursor.offset.load(Ordering::SeqCst);
+
+                if self.exceeds(cursor, pos, offset) {
+                    cursor.overflowed.store(true, Ordering::SeqCst);
+                }
+            }
+
+            cursor.waker.wake();
+        }
+
+        self.trim();
+    }
+
+    /// Discard entries that every subscriber has either read or fallen too far behind on.
+    /// Blocking subscribers never fall behind, so entries they haven't read are kept.
+    fn trim(&mut self) {
+        let mut min_pos = self.head();
+        let mut block_pos = self.head();
+        let mut max_messages = 1;
+        let mut max_bytes = 0;
+
+        for cursor in self.subscribers.iter().filter(|c| !c.overflowed.load(Ordering::SeqCst)) {
+            let pos = cursor.pos.load(Ordering::SeqCst);
+            min_pos = min_pos.min(pos);
+            max_messages = max_messages.max(cursor.config.max_messages);
+            max_bytes = max_bytes.max(cursor.config.max_bytes as u64);
+
+            if cursor.config.policy == SlowClientPolicy::Block {
+                block_pos = block_pos.min(pos);
+            }
+        }
+
+        while let Some(entry) = self.entries.front() {
+            let read = self.first < min_pos;
+            let too_old = self.first < block_pos && self.entries.len() > 1 &&
+                (self.entries.len() > max_messages || self.offset - entry.offset > max_bytes);
+
+            if !read && !too_old {
+                break;
+            }
+
+            self.entries.pop_front();
+            self.first += 1;
+        }
+    }
+
+    /// Find the oldest position a lagging subscriber can continue reading from without exceeding
+    /// its limits.
+    fn catch_up(&self, config: &QueueConfig) -> (u64, u64) {
+        let mut pos = self.head();
+        let mut offset = self.offset;
+
+        while pos > self.first {
+            let entry = self.get(pos - 1);
+            let count = self.head() - pos + 1;
+
+            if count > 1 && (count > config.max_messages as u64 || self.offset - entry.offset > config.max_bytes as u64) {
+                break;
+            }
+
+            pos -= 1;
+            offset = entry.offset;
+        }
+
+        (pos, offset)
+    }
+}
+
+impl<T> Subscriber<T> {
+    /// Whether the client was disconnected for not keeping up with output.
+    pub fn overflowed(&self) -> bool {
+        self.cursor.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+impl<T: Output> Stream for Subscriber<T> {
+    type Item = Arc<T>;
+
+    /// Yields output for this client, skipping ahead if it has fallen behind and the policy
+    /// allows it. Ends when the client has been disconnected for being too slow, or when the
+    /// broadcast has been closed and everything published has been read.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let cursor = &self.cursor;
+        cursor.waker.register(cx.waker());
+
+        let ring = self.broadcast.ring.read().unwrap();
+
+        loop {
+            if cursor.overflowed.load(Ordering::SeqCst) {
+                return Poll::Ready(None);
+            }
+
+            let pos = cursor.pos.load(Ordering::SeqCst);
+            let offset = cursor.offset.load(Ordering::SeqCst);
+
+            if ring.exceeds(cursor, pos, offset) {
+                if cursor.config.policy == SlowClientPolicy::Disconnect {
+                    cursor.overflowed.store(true, Ordering::SeqCst);
+                    return Poll::Ready(None);
+                }
+
+                let (new_pos, new_offset) = ring.catch_up(&cursor.config);
+                cursor.pos.store(new_pos, Ordering::SeqCst);
+                cursor.offset.store(new_offset, Ordering::SeqCst);
+                self.broadcast.read_done();
+
+                let marker = (self.broadcast.skipped)((new_pos - pos) as usize, (new_offset - offset) as usize);
+                return Poll::Ready(Some(Arc::new(marker)));
+            }
+
+            if pos == ring.head() {
+                return if ring.closed { Poll::Ready(None) } else { Poll::Pending };
+            }
+
+            let entry = ring.get(pos);
+            cursor.pos.store(pos + 1, Ordering::SeqCst);
+            cursor.offset.store(offset + entry.data.size() as u64, Ordering::SeqCst);
+            self.broadcast.read_done();
+
+            let wanted = entry.source.is_none_or(|s| cursor.streams.includes(s));
+
+            if wanted && entry.audience.includes(cursor.client) {
+                return Poll::Ready(Some(entry.data.clone()));
+            }
+        }
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        let mut ring = self.broadcast.ring.write().unwrap();
+        ring.subscribers.retain(|c| !Arc::ptr_eq(c, &self.cursor));
+        ring.trim();
+        drop(ring);
+
+        if self.broadcast.waiting.load(Ordering::SeqCst) > 0 {
+            self.broadcast.space.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio::stream::StreamExt;
+
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct Message(String);
+
+    impl Output for Message {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    fn message(s: &str) -> Message {
+        Message(s.to_owned())
+    }
+
+    fn broadcast(scrollback: ScrollbackLimit) -> Arc<Broadcast<Message>> {
+        Broadcast::new(scrollback, |messages, bytes| Message(format!("skipped {} messages, {} bytes", messages, bytes)))
+    }
+
+    fn client(port: u16) -> ClientRef {
+        ClientRef::Net(([127, 0, 0, 1], port).into())
+    }
+
+    fn queue(policy: SlowClientPolicy, max_messages: usize) -> QueueConfig {
+        QueueConfig { policy, max_messages, max_bytes: 1024 }
+    }
+
+    /// Read everything the subscriber has received so far.
+    fn read_all(subscriber: &mut Subscriber<Message>) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        while let Some(Some(message)) = subscriber.next().now_or_never() {
+            messages.push(message.0.clone());
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn everyone_receives_output() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut a) = output.subscribe(client(1), QueueConfig::default(), Streams::ALL);
+        let (_, mut b) = output.subscribe(client(2), QueueConfig::default(), Streams::ALL);
+
+        output.publish_output(message("one"), Source::Stdout).await;
+        output.publish_output(message("two"), Source::Stdout).await;
+
+        assert_eq!(read_all(&mut a), vec!["one", "two"]);
+        assert_eq!(read_all(&mut b), vec!["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn audiences_and_streams() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut a) = output.subscribe(client(1), QueueConfig::default(), Streams::ALL);
+        let (_, mut b) = output.subscribe(client(2), QueueConfig::default(), Streams { stdout: true, stderr: false });
+
+        output.send(message("for a"), Audience::Only(client(1))).await;
+        output.send(message("not for a"), Audience::Except(client(1))).await;
+        output.publish_output(message("error"), Source::Stderr).await;
+
+        assert_eq!(read_all(&mut a), vec!["for a", "error"]);
+        assert_eq!(read_all(&mut b), vec!["not for a"]);
+    }
+
+    #[tokio::test]
+    async fn scrollback() {
+        let output = broadcast(ScrollbackLimit::Lines(2));
+
+        for line in &["one", "two", "three"] {
+            output.publish_output(message(line), Source::Stdout).await;
+        }
+
+        // messages from conlink aren't replayed
+        output.send(message("notice"), Audience::All).await;
+
+        let (scrollback, mut subscriber) = output.subscribe(client(1), QueueConfig::default(), Streams::ALL);
+        let scrollback: Vec<_> = scrollback.iter().map(|m| m.0.clone()).collect();
+        assert_eq!(scrollback, vec!["two", "three"]);
+        assert_eq!(read_all(&mut subscriber), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut subscriber) = output.subscribe(client(1), QueueConfig::default(), Streams::ALL);
+
+        output.publish_output(message("last"), Source::Stdout).await;
+        output.close();
+
+        assert_eq!(subscriber.next().await, Some(Arc::new(message("last"))));
+        assert_eq!(subscriber.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn drop_policy_skips() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut slow) = output.subscribe(client(1), queue(SlowClientPolicy::Drop, 2), Streams::ALL);
+
+        for line in &["1", "2", "3", "4", "5"] {
+            output.publish_output(message(line), Source::Stdout).await;
+        }
+
+        assert_eq!(read_all(&mut slow), vec!["skipped 3 messages, 3 bytes", "4", "5"]);
+        assert!(!slow.overflowed());
+    }
+
+    #[tokio::test]
+    async fn disconnect_policy() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut slow) = output.subscribe(client(1), queue(SlowClientPolicy::Disconnect, 2), Streams::ALL);
+        let (_, mut fast) = output.subscribe(client(2), queue(SlowClientPolicy::Disconnect, 2), Streams::ALL);
+
+        for line in &["1", "2", "3"] {
+            output.publish_output(message(line), Source::Stdout).await;
+            assert_eq!(read_all(&mut fast), vec![line.to_string()]);
+        }
+
+        assert_eq!(slow.next().await, None);
+        assert!(slow.overflowed());
+        assert!(!fast.overflowed());
+    }
+
+    #[tokio::test]
+    async fn block_policy_waits() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut slow) = output.subscribe(client(1), queue(SlowClientPolicy::Block, 2), Streams::ALL);
+
+        output.publish_output(message("1"), Source::Stdout).await;
+        output.publish_output(message("2"), Source::Stdout).await;
+
+        let publish = output.publish_output(message("3"), Source::Stdout);
+        futures::pin_mut!(publish);
+        assert!(futures::poll!(&mut publish).is_pending());
+
+        assert_eq!(read_all(&mut slow), vec!["1", "2"]);
+        assert!(futures::poll!(&mut publish).is_ready());
+        assert_eq!(read_all(&mut slow), vec!["3"]);
+    }
+
+    #[tokio::test]
+    async fn block_policy_drop_subscriber() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, slow) = output.subscribe(client(1), queue(SlowClientPolicy::Block, 1), Streams::ALL);
+
+        output.publish_output(message("1"), Source::Stdout).await;
+
+        let publish = output.publish_output(message("2"), Source::Stdout);
+        futures::pin_mut!(publish);
+        assert!(futures::poll!(&mut publish).is_pending());
+
+        // a client disconnecting doesn't leave output stuck
+        drop(slow);
+        assert!(futures::poll!(&mut publish).is_ready());
+    }
+
+    #[tokio::test]
+    async fn only_output_a_blocking_subscriber_receives_waits() {
+        let output = broadcast(ScrollbackLimit::Lines(0));
+        let (_, mut slow) = output.subscribe(client(1), queue(SlowClientPolicy::Block, 1), Streams { stdout: true, stderr: false });
+
+        output.publish_output(message("1"), Source::Stdout).await;
+
+        // the subscriber doesn't receive error output, so it isn't waited for
+        assert!(output.publish_output(message("error"), Source::Stderr).now_or_never().is_some());
+
+        // messages from conlink sent on the subscriber's behalf, like telling it that its input
+        // was refused, mustn't wait for it, since it may be the one waiting for them
+        assert!(output.send(message("to self"), Audience::Only(client(1))).now_or_never().is_some());
+        assert!(output.send(message("to all"), Audience::All).now_or_never().is_some());
+
+        // those messages are kept for it even beyond its limit
+        assert_eq!(read_all(&mut slow), vec!["1", "to self", "to all"]);
+    }
+}
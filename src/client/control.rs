@@ -0,0 +1,334 @@
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::mem;
+
+use libc::c_int;
+
+/// Input lines starting with this character are checked for conlink commands.
+pub const COMMAND_PREFIX: u8 = b'~';
+
+/// The longest line held back while a command may be being typed. Longer lines can't be commands.
+const MAX_COMMAND_LENGTH: usize = 256;
+
+/// The signals clients can send by name.
+const SIGNALS: &[(&str, c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+/// A command sent by a client to conlink itself instead of the program.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Command {
+    /// Take the input lock, or queue for it if someone else holds it.
+    Lock,
+
+    /// Release the input lock, or leave the queue for it.
+    Unlock,
+
+    /// Show who holds the input lock.
+    Status,
+
+    /// Send a signal to the program.
+    Signal(Signal),
+}
+
+/// A signal a client sends to the program.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Signal {
+    pub number: c_int,
+    /// Whether to send it to the program's whole process group, including anything it started.
+    pub group: bool,
+}
+
+impl Signal {
+    /// Parse a signal given by name, with or without the SIG prefix, or by number.
+    pub fn parse(name: &[u8], group: bool) -> Result<Self, String> {
+        let name = String::from_utf8_lossy(name);
+        let name = name.trim();
+        let upper = name.to_ascii_uppercase();
+        let short = upper.strip_prefix("SIG").unwrap_or(&upper);
+
+        let number = match name.parse::<c_int>() {
+            Ok(number) if number > 0 && number <= libc::SIGRTMAX() => Some(number),
+            Ok(_) => None,
+            Err(_) => SIGNALS.iter().find(|(n, _)| *n == short).map(|&(_, number)| number),
+        };
+
+        match number {
+            Some(number) => Ok(Signal { number, group }),
+            None => Err(format!("unknown signal '{}'", name)),
+        }
+    }
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match SIGNALS.iter().find(|&&(_, number)| number == self.number) {
+            Some((name, _)) => write!(f, "SIG{}", name),
+            None => write!(f, "signal {}", self.number),
+        }
+    }
+}
+
+/// What a piece of client input turned out to be.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Input<'a> {
+    Command(Command),
+
+    /// A command that couldn't be understood, with the reason.
+    Invalid(String),
+
+    /// Data to pass to the program. Input starting with two prefix characters has the first one
+    /// removed, so that lines that look like commands can still be sent to the program.
+    Data(&'a [u8]),
+}
+
+impl<'a> Input<'a> {
+    pub fn parse(data: &'a [u8]) -> Self {
+        if data.first() != Some(&COMMAND_PREFIX) {
+            return Input::Data(data);
+        }
+
+        if data.get(1) == Some(&COMMAND_PREFIX) {
+            return Input::Data(&data[1..]);
+        }
+
+        let mut line = &data[1..];
+        while let Some((b'\r', rest)) | Some((b'\n', rest)) = line.split_last() {
+            line = rest;
+        }
+
+        match line {
+            b"lock" => Input::Command(Command::Lock),
+            b"unlock" => Input::Command(Command::Unlock),
+            b"status" => Input::Command(Command::Status),
+            _ => {
+                let signal = match (line.strip_prefix(b"signal "), line.strip_prefix(b"signal-group ")) {
+                    (Some(name), _) => Signal::parse(name, false),
+                    (_, Some(name)) => Signal::parse(name, true),
+                    _ => return Input::Data(data),
+                };
+
+                match signal {
+                    Ok(signal) => Input::Command(Command::Signal(signal)),
+                    Err(e) => Input::Invalid(e),
+                }
+            }
+        }
+    }
+}
+
+/// A piece of input from a client sending a stream of bytes.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Piece {
+    /// A complete line starting with the command prefix, to be checked for commands.
+    Line(Vec<u8>),
+
+    /// Input to pass on as it is.
+    Data(Vec<u8>),
+}
+
+impl Piece {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Piece::Line(data) | Piece::Data(data) => data,
+        }
+    }
+}
+
+/// Picks out the lines that may be commands from input sent as a stream of bytes, which may
+/// arrive one key at a time. Like ssh escapes, input following the command prefix at the start
+/// of a line is held back until the line ends, and a doubled prefix is passed on as a single one
+/// right away.
+#[derive(Debug)]
+pub struct LineState {
+    /// Whether the next byte starts a new line.
+    line_start: bool,
+    /// The line being held back, starting with the command prefix, if there is one.
+    pending: Vec<u8>,
+}
+
+impl LineState {
+    pub fn new() -> Self {
+        LineState {
+            line_start: true,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Split more input into lines to check for commands and data to pass on.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        // the start of the input not yet added to a piece
+        let mut start = 0;
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let b = data[pos];
+            pos += 1;
+
+            if self.pending.is_empty() {
+                if b == COMMAND_PREFIX && self.line_start {
+                    if start < pos - 1 {
+                        pieces.push(Piece::Data(data[start..pos - 1].to_vec()));
+                    }
+
+                    self.pending.push(b);
+                    start = pos;
+                } else {
+                    self.line_start = b == b'\r' || b == b'\n';
+                }
+
+                continue;
+            }
+
+            match b {
+                b'\r' | b'\n' => {
+                    self.pending.push(b);
+
+                    // a line feed following a carriage return ends the same line
+                    if b == b'\r' && data.get(pos) == Some(&b'\n') {
+                        self.pending.push(b'\n');
+                        pos += 1;
+                    }
+
+                    pieces.push(Piece::Line(mem::take(&mut self.pending)));
+                    self.line_start = true;
+                }
+                // a client that leaves line editing to the program can still correct a command
+                b'\x08' | b'\x7f' => {
+                    self.pending.pop();
+                }
+                COMMAND_PREFIX if self.pending == [COMMAND_PREFIX] => {
+                    self.pending.clear();
+                    pieces.push(Piece::Data(vec![COMMAND_PREFIX]));
+                    self.line_start = false;
+                }
+                _ => {
+                    self.pending.push(b);
+
+                    if self.pending.len() > MAX_COMMAND_LENGTH {
+                        pieces.push(Piece::Data(mem::take(&mut self.pending)));
+                        self.line_start = false;
+                    }
+                }
+            }
+
+            start = pos;
+        }
+
+        if start < data.len() {
+            pieces.push(Piece::Data(data[start..].to_vec()));
+        }
+
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(data: &[u8]) -> Piece {
+        Piece::Line(data.to_vec())
+    }
+
+    fn data(data: &[u8]) -> Piece {
+        Piece::Data(data.to_vec())
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Input::parse(b"~lock"), Input::Command(Command::Lock));
+        assert_eq!(Input::parse(b"~unlock\r\n"), Input::Command(Command::Unlock));
+        assert_eq!(Input::parse(b"~status\n"), Input::Command(Command::Status));
+    }
+
+    #[test]
+    fn parse_data() {
+        assert_eq!(Input::parse(b"lock"), Input::Data(b"lock"));
+        assert_eq!(Input::parse(b""), Input::Data(b""));
+        assert_eq!(Input::parse(b"~locked\n"), Input::Data(b"~locked\n"));
+        assert_eq!(Input::parse(b"~~lock\n"), Input::Data(b"~lock\n"));
+        assert_eq!(Input::parse(b" ~lock"), Input::Data(b" ~lock"));
+    }
+
+    #[test]
+    fn parse_signals() {
+        assert_eq!(Signal::parse(b"INT", false), Ok(Signal { number: libc::SIGINT, group: false }));
+        assert_eq!(Signal::parse(b"sigterm", true), Ok(Signal { number: libc::SIGTERM, group: true }));
+        assert_eq!(Signal::parse(b" 9\r", false), Ok(Signal { number: libc::SIGKILL, group: false }));
+
+        for name in &[&b""[..], b"SIG", b"NOPE", b"0", b"-1", b"1000"] {
+            assert!(Signal::parse(name, false).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn parse_signal_commands() {
+        assert_eq!(Input::parse(b"~signal HUP\n"), Input::Command(Command::Signal(Signal { number: libc::SIGHUP, group: false })));
+        assert_eq!(Input::parse(b"~signal-group USR1"), Input::Command(Command::Signal(Signal { number: libc::SIGUSR1, group: true })));
+        assert_eq!(Input::parse(b"~signal NOPE"), Input::Invalid("unknown signal 'NOPE'".to_owned()));
+        assert_eq!(Input::parse(b"~signal"), Input::Data(b"~signal"));
+    }
+
+    #[test]
+    fn display_signals() {
+        assert_eq!(Signal { number: libc::SIGINT, group: false }.to_string(), "SIGINT");
+        assert_eq!(Signal { number: libc::SIGRTMIN(), group: false }.to_string(), format!("signal {}", libc::SIGRTMIN()));
+    }
+
+    #[test]
+    fn feed_plain_input() {
+        let mut state = LineState::new();
+        assert_eq!(state.feed(b"echo hi\nls ~\n"), vec![data(b"echo hi\nls ~\n")]);
+    }
+
+    #[test]
+    fn feed_whole_lines() {
+        let mut state = LineState::new();
+        assert_eq!(state.feed(b"a\n~lock\r\nb"), vec![data(b"a\n"), line(b"~lock\r\n"), data(b"b")]);
+        assert_eq!(state.feed(b"~unlock\n"), vec![data(b"~unlock\n")]);
+    }
+
+    #[test]
+    fn feed_key_at_a_time() {
+        let mut state = LineState::new();
+        let mut pieces = Vec::new();
+
+        for b in b"~lokc\x7f\x7fck\r" {
+            pieces.extend(state.feed(&[*b]));
+        }
+
+        assert_eq!(pieces, vec![line(b"~lock\r")]);
+        assert_eq!(state.feed(b"x"), vec![data(b"x")]);
+    }
+
+    #[test]
+    fn feed_doubled_prefix() {
+        let mut state = LineState::new();
+        assert_eq!(state.feed(b"~"), vec![]);
+        assert_eq!(state.feed(b"~"), vec![data(b"~")]);
+        assert_eq!(state.feed(b"lock\n"), vec![data(b"lock\n")]);
+        assert_eq!(state.feed(b"~~x"), vec![data(b"~"), data(b"x")]);
+    }
+
+    #[test]
+    fn feed_long_line() {
+        let mut state = LineState::new();
+        let mut input = vec![COMMAND_PREFIX];
+        input.extend(vec![b'a'; MAX_COMMAND_LENGTH]);
+
+        assert_eq!(state.feed(&input), vec![data(&input)]);
+        assert_eq!(state.feed(b"~lock\n"), vec![data(b"~lock\n")]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::borrow::Cow;
+use std::process::ExitStatus;
+
+use crate::client::{ClientRef, Identity};
+use crate::client::broadcast::Source;
+use crate::client::queue::Output;
+use crate::framing::Framing;
+
+/// Something sent to clients. Clients using the multiplexed protocol receive every event as a
+/// typed frame, other clients only see the ones that have a plain text form.
+#[derive(Debug)]
+pub enum Event {
+    /// Output from the program.
+    Output {
+        source: Source,
+        data: Vec<u8>,
+        /// The output as shown to clients not using the multiplexed protocol, if it differs,
+        /// like labeled error output.
+        labeled: Option<Vec<u8>>,
+    },
+
+    /// Input a client sent to the program, echoed to the other clients.
+    Input { from: ClientRef, data: Vec<u8> },
+
+    /// The program has exited.
+    Exit(ExitStatus),
+
+    /// A client has connected.
+    Join(Identity),
+
+    /// A client has disconnected.
+    Leave(ClientRef),
+
+    /// A message from conlink itself.
+    Notice(String),
+
+    /// A message from conlink about something going wrong, like input being refused.
+    Error(String),
+
+    /// Output was skipped because the client couldn't keep up.
+    Skipped { messages: usize, bytes: usize },
+
+    /// Sent periodically to clients using the multiplexed protocol, to show the connection is
+    /// alive.
+    Heartbeat,
+}
+
+impl Event {
+    /// The data sent to a client not using the multiplexed protocol, if any.
+    pub fn render(&self, framing: Framing) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Event::Output { labeled: Some(data), .. } => Some(Cow::Borrowed(data)),
+            Event::Output { data, .. } => Some(Cow::Borrowed(data)),
+            Event::Input { data, .. } => Some(Cow::Borrowed(data)),
+            Event::Notice(text) | Event::Error(text) => Some(Cow::Owned(framing.notice(text))),
+            Event::Skipped { messages, bytes } => Some(Cow::Owned(framing.skipped(*messages, *bytes))),
+            Event::Exit(_) | Event::Join(_) | Event::Leave(_) | Event::Heartbeat => None,
+        }
+    }
+}
+
+impl Output for Event {
+    fn size(&self) -> usize {
+        match self {
+            Event::Output { data, labeled, .. } => data.len() + labeled.as_ref().map_or(0, |l| l.len()),
+            Event::Input { data, .. } => data.len(),
+            Event::Notice(text) | Event::Error(text) => text.len(),
+            _ => 0,
+        }
+    }
+}
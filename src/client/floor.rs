@@ -0,0 +1,226 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::client::{ClientRef, Identity};
+use crate::client::broadcast::Audience;
+
+pub type Notices = Vec<(Audience, String)>;
+
+/// Tracks which client may currently send input to the program, when only one client at a time
+/// is allowed to.
+#[derive(Debug, Default)]
+pub struct Floor {
+    holder: Option<Identity>,
+    queue: VecDeque<Identity>,
+    /// Clients that have been told their input is ignored since the lock last changed hands.
+    refused: HashSet<ClientRef>,
+}
+
+impl Floor {
+    pub fn new() -> Self {
+        Floor::default()
+    }
+
+    /// Check whether the client may send input. If it may not, the notices to send are returned,
+    /// which are only non-empty the first time until the lock changes hands.
+    pub fn check(&mut self, who: &Identity) -> Result<(), Notices> {
+        let msg = match &self.holder {
+            Some(h) if h.client == who.client => return Ok(()),
+            Some(h) => format!("input lock is held by {}, input is ignored; send ~lock to request it", h),
+            None => "nobody holds the input lock, input is ignored; send ~lock to take it".to_owned(),
+        };
+
+        if self.refused.insert(who.client) {
+            Err(vec![(Audience::Only(who.client), msg)])
+        } else {
+            Err(Vec::new())
+        }
+    }
+
+    /// The client currently holding the lock, if any.
+    pub fn holder(&self) -> Option<ClientRef> {
+        self.holder.as_ref().map(|h| h.client)
+    }
+
+    pub fn lock(&mut self, who: &Identity) -> Notices {
+        match &self.holder {
+            None => {
+                self.holder = Some(who.clone());
+                self.refused.clear();
+                vec![(Audience::All, format!("{} now holds the input lock", who))]
+            }
+            Some(h) if h.client == who.client => {
+                vec![(Audience::Only(who.client), "you already hold the input lock".to_owned())]
+            }
+            Some(h) => {
+                let mut notices = vec![(Audience::Only(who.client), format!("input lock is held by {}, you will get it when it is released", h))];
+
+                if !self.queue.iter().any(|q| q.client == who.client) {
+                    self.queue.push_back(who.clone());
+                    notices.push((Audience::Only(h.client), format!("{} requests the input lock", who)));
+                }
+
+                notices
+            }
+        }
+    }
+
+    pub fn unlock(&mut self, who: &Identity) -> Notices {
+        match &self.holder {
+            Some(h) if h.client == who.client => self.pass_on(format!("{} released the input lock", who)),
+            _ => {
+                let len = self.queue.len();
+                self.queue.retain(|q| q.client != who.client);
+
+                let msg = if self.queue.len() != len {
+                    "you are no longer waiting for the input lock"
+                } else {
+                    "you don't hold the input lock"
+                };
+
+                vec![(Audience::Only(who.client), msg.to_owned())]
+            }
+        }
+    }
+
+    pub fn status(&self, who: &Identity) -> Notices {
+        let mut msg = match &self.holder {
+            Some(h) => format!("input lock is held by {}", h),
+            None => "input lock is free".to_owned(),
+        };
+
+        if !self.queue.is_empty() {
+            let waiting: Vec<_> = self.queue.iter().map(|q| q.to_string()).collect();
+            msg.push_str(&format!(", waiting: {}", waiting.join(", ")));
+        }
+
+        vec![(Audience::Only(who.client), msg)]
+    }
+
+    /// Release the lock held by a client that has disconnected.
+    pub fn remove(&mut self, client: ClientRef) -> Notices {
+        self.queue.retain(|q| q.client != client);
+        self.refused.remove(&client);
+
+        match &self.holder {
+            Some(h) if h.client == client => self.pass_on(format!("{} disconnected, releasing the input lock", h)),
+            _ => Vec::new(),
+        }
+    }
+
+    fn pass_on(&mut self, mut msg: String) -> Notices {
+        self.holder = self.queue.pop_front();
+        self.refused.clear();
+
+        if let Some(h) = &self.holder {
+            msg.push_str(&format!(", {} now holds it", h));
+        }
+
+        vec![(Audience::All, msg)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Role;
+
+    fn client(port: u16) -> Identity {
+        Identity::anonymous(ClientRef::Net(([127, 0, 0, 1], port).into()), Role::Operator)
+    }
+
+    #[test]
+    fn nobody_holds_it_at_first() {
+        let mut floor = Floor::new();
+        let a = client(1);
+
+        assert_eq!(floor.holder(), None);
+        assert_eq!(floor.check(&a).unwrap_err().len(), 1);
+        // the client is only told once
+        assert_eq!(floor.check(&a).unwrap_err().len(), 0);
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let mut floor = Floor::new();
+        let (a, b) = (client(1), client(2));
+
+        assert_eq!(floor.lock(&a), vec![(Audience::All, "127.0.0.1:1 now holds the input lock".to_owned())]);
+        assert_eq!(floor.holder(), Some(a.client));
+        assert!(floor.check(&a).is_ok());
+        assert!(floor.check(&b).is_err());
+
+        assert_eq!(floor.unlock(&b), vec![(Audience::Only(b.client), "you don't hold the input lock".to_owned())]);
+        assert_eq!(floor.holder(), Some(a.client));
+
+        assert_eq!(floor.unlock(&a), vec![(Audience::All, "127.0.0.1:1 released the input lock".to_owned())]);
+        assert_eq!(floor.holder(), None);
+    }
+
+    #[test]
+    fn handed_over_in_order() {
+        let mut floor = Floor::new();
+        let (a, b, c) = (client(1), client(2), client(3));
+
+        floor.lock(&a);
+        let notices = floor.lock(&b);
+        assert_eq!(notices.len(), 2);
+        assert_eq!(notices[1], (Audience::Only(a.client), "127.0.0.1:2 requests the input lock".to_owned()));
+
+        // asking again doesn't queue the client twice or bother the holder again
+        assert_eq!(floor.lock(&b).len(), 1);
+        floor.lock(&c);
+
+        assert_eq!(floor.status(&c), vec![(Audience::Only(c.client), "input lock is held by 127.0.0.1:1, waiting: 127.0.0.1:2, 127.0.0.1:3".to_owned())]);
+
+        assert_eq!(floor.unlock(&a), vec![(Audience::All, "127.0.0.1:1 released the input lock, 127.0.0.1:2 now holds it".to_owned())]);
+        assert_eq!(floor.holder(), Some(b.client));
+
+        floor.unlock(&b);
+        assert_eq!(floor.holder(), Some(c.client));
+    }
+
+    #[test]
+    fn leave_the_queue() {
+        let mut floor = Floor::new();
+        let (a, b) = (client(1), client(2));
+
+        floor.lock(&a);
+        floor.lock(&b);
+        assert_eq!(floor.unlock(&b), vec![(Audience::Only(b.client), "you are no longer waiting for the input lock".to_owned())]);
+
+        floor.unlock(&a);
+        assert_eq!(floor.holder(), None);
+    }
+
+    #[test]
+    fn holder_disconnects() {
+        let mut floor = Floor::new();
+        let (a, b, c) = (client(1), client(2), client(3));
+
+        floor.lock(&a);
+        floor.lock(&b);
+        floor.lock(&c);
+
+        // a client leaving the queue doesn't concern anyone else
+        assert_eq!(floor.remove(b.client), vec![]);
+
+        assert_eq!(floor.remove(a.client), vec![(Audience::All, "127.0.0.1:1 disconnected, releasing the input lock, 127.0.0.1:3 now holds it".to_owned())]);
+        assert_eq!(floor.holder(), Some(c.client));
+    }
+
+    #[test]
+    fn refused_clients_are_told_again_after_a_handover() {
+        let mut floor = Floor::new();
+        let (a, b, c) = (client(1), client(2), client(3));
+
+        floor.lock(&a);
+        assert_eq!(floor.check(&c).unwrap_err().len(), 1);
+        assert_eq!(floor.check(&c).unwrap_err().len(), 0);
+
+        floor.lock(&b);
+        floor.unlock(&a);
+
+        let notices = floor.check(&c).unwrap_err();
+        assert_eq!(notices, vec![(Audience::Only(c.client), "input lock is held by 127.0.0.1:2, input is ignored; send ~lock to request it".to_owned())]);
+    }
+}
@@ -1,26 +1,67 @@
 use std::fmt::{Display, Formatter};
 use std::fmt;
+use std::io;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use tokio::net::TcpStream;
-use tokio::process::ChildStdin;
-use tokio::sync::{mpsc, Mutex};
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::macros::support::{Pin, Poll};
+use tokio::stream::{Stream, StreamExt};
+use tokio::sync::Mutex;
+use tokio::time::Interval;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
 
-use async_trait::async_trait;
+use self::broadcast::{Broadcast, Streams};
+use self::control::Command;
+use self::event::Event;
+use self::mux::MuxClient;
+use self::net::NetClient;
+use self::protocol::{Mode, Request};
+use self::queue::QueueConfig;
+use self::shared::{Outbox, Shared};
+use self::telnet::TelnetClient;
+use self::term::TermClient;
+use self::ws::WsClient;
+use crate::framing::Framing;
+use crate::pty::WindowSize;
+use crate::telnet::TelnetStream;
 
-// TODO: abstract this out so that not two versions of the same code are needed
-pub mod str;
-pub mod bin;
+pub mod broadcast;
+pub mod control;
+pub mod event;
+pub mod floor;
+pub mod mux;
+pub mod net;
+pub mod protocol;
+pub mod queue;
+pub mod scrollback;
+pub mod shared;
+pub mod telnet;
+pub mod term;
+pub mod window;
+pub mod ws;
 
-pub type Tx<T> = mpsc::Sender<T>;
-
-pub type Rx<T> = mpsc::Receiver<T>;
+pub type Rx = broadcast::Subscriber<Event>;
 
 #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
 pub enum ClientRef {
     Term,
     Net(SocketAddr),
+    Unix(UnixPeer),
+}
+
+/// A client connected through a Unix domain socket, identified by the credentials of the
+/// connecting process.
+#[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub struct UnixPeer {
+    /// Distinguishes multiple connections from the same process.
+    pub id: usize,
+    pub pid: u32,
+    pub uid: u32,
 }
 
 impl Display for ClientRef {
@@ -28,37 +69,382 @@ impl Display for ClientRef {
         match self {
             ClientRef::Term => write!(f, "<stdout>"),
             ClientRef::Net(a) => write!(f, "{}", a),
+            ClientRef::Unix(p) => write!(f, "unix#{} (uid {}, pid {})", p.id, p.uid, p.pid),
         }
     }
 }
 
-#[async_trait]
-pub trait Client<S> where S: Shared {
-    async fn new_term(state: Arc<Mutex<S>>) -> Self;
+/// What a client is allowed to do.
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub enum Role {
+    /// Receives output, but can't send input to the program.
+    Observer,
+
+    /// Receives output and can send input to the program.
+    Operator,
+}
+
+/// A client along with the user it has authenticated as, if any.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Identity {
+    pub client: ClientRef,
+    pub user: Option<Arc<str>>,
+    pub role: Role,
+}
 
-    async fn new_net(stream: TcpStream, state: Arc<Mutex<S>>) -> Self;
+impl Identity {
+    pub fn anonymous(client: ClientRef, role: Role) -> Self {
+        Identity { client, user: None, role }
+    }
+}
 
-    async fn process(self) -> Result<(), Box<dyn std::error::Error>>;
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{} ({})", user, self.client),
+            None => write!(f, "{}", self.client),
+        }
+    }
 }
 
-#[async_trait]
-pub trait Shared {
-    type Data: ?Sized;
+/// A bidirectional stream a network client is connected through.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// The protocol spoken over an established network connection.
+pub enum Transport {
+    /// Program input and output are sent as-is.
+    Raw(Box<dyn Connection>),
+
+    /// Program input and output are sent in WebSocket messages.
+    WebSocket(Box<WebSocketStream<Box<dyn Connection>>>),
+
+    /// Program input and output are sent using the telnet protocol.
+    Telnet(Box<TelnetStream>),
+}
 
-    fn new(stdin: ChildStdin, echo: bool) -> Self;
+impl Transport {
+    /// Send a message from conlink itself, before the connection is handed to a client.
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        match self {
+            Transport::Raw(stream) => stream.write_all(text.as_bytes()).await,
+            Transport::Telnet(stream) => stream.write_all(text.as_bytes()).await,
+            Transport::WebSocket(ws) => {
+                let text = text.trim_end_matches('\n').to_owned();
+                ws.send(WsMessage::Text(text)).await.map_err(io::Error::other)
+            }
+        }
+    }
 
-    /// Send a line of text to the program's input.
-    async fn write_to_stdin(&mut self, line: &Self::Data, from: ClientRef);
+    pub async fn close(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Raw(stream) => stream.shutdown().await,
+            Transport::Telnet(stream) => stream.shutdown().await,
+            Transport::WebSocket(ws) => ws.close().await.map_err(io::Error::other),
+        }
+    }
 
-    /// Send a line of text to all connected clients.
-    async fn write_output(&mut self, line: &Self::Data);
+    /// Find out whether the client wants to use the multiplexed protocol. WebSocket clients ask
+    /// for it during the handshake, so `mode` is what was agreed on then. Clients on raw
+    /// connections can only ask for it if `multiplexed` is set, since they are made to wait
+    /// while conlink checks. Telnet clients don't use it.
+    pub async fn negotiate(self, mode: Mode, multiplexed: bool) -> io::Result<(Transport, Mode)> {
+        match self {
+            Transport::Raw(stream) if !multiplexed => Ok((Transport::Raw(stream), Mode::Plain)),
+            Transport::Raw(stream) => {
+                let (stream, mode) = protocol::negotiate(stream).await?;
+                Ok((Transport::Raw(stream), mode))
+            }
+            Transport::Telnet(stream) => Ok((Transport::Telnet(stream), Mode::Plain)),
+            ws => Ok((ws, mode)),
+        }
+    }
 }
 
+/// How many messages from a client are handled before letting other tasks run. A client sending
+/// input continuously would otherwise keep everything else from running.
+const MESSAGE_BATCH: usize = 64;
+
+/// Sent to observers the first time they try to send input.
+pub const READ_ONLY_NOTICE: &str = "this connection is read-only, input is ignored";
+
 #[derive(Debug)]
-pub enum Message<T> {
+pub enum Message {
     /// A message containing a line of text to be sent to the program.
-    ToProgram(T),
+    ToProgram(Vec<u8>),
+
+    /// A command to conlink itself, sent in a control frame.
+    Command(Command),
+
+    /// The size of the client's terminal changed.
+    Resize(WindowSize),
+
+    /// An event to be sent to the client.
+    ToClient(Arc<Event>),
+
+    /// It is time to send a heartbeat to the client.
+    Heartbeat,
+}
+
+/// A client connected to the running program.
+/// In most cases, this is connected through the network.
+pub struct Client {
+    state: Arc<Mutex<Shared>>,
+    identity: Identity,
+    framing: Framing,
+    mode: Mode,
+    output: Arc<Broadcast<Event>>,
+    rx: Rx,
+    /// Output buffered before this client connected, to be sent before any new output.
+    scrollback: std::vec::IntoIter<Arc<Event>>,
+    /// Ticks when a heartbeat is due, for clients using the multiplexed protocol.
+    heartbeat: Option<Interval>,
+    inner: ClientImpl,
+}
 
-    /// A message containing a line of text to be send to connected clients.
-    FromProgram(T),
-}
\ No newline at end of file
+enum ClientImpl {
+    Term(TermClient),
+    Net(NetClient),
+    Mux(MuxClient),
+    Ws(WsClient),
+    Telnet(TelnetClient),
+}
+
+impl Client {
+    async fn new(inner: ClientImpl, identity: Identity, mode: Mode, queue: QueueConfig, streams: Streams, state: Arc<Mutex<Shared>>) -> Self {
+        let (framing, output, scrollback, rx, outbox) = {
+            let mut state = state.lock().await;
+            let (scrollback, rx) = state.output().subscribe(inner.get_ref(), queue, streams);
+            // subscribed first, so that the client is told about joining itself
+            state.add_client(&identity);
+            (state.framing(), state.output().clone(), scrollback, rx, state.take_outbox())
+        };
+
+        publish(&output, outbox).await;
+
+        let heartbeat = match mode {
+            Mode::Multiplexed => {
+                let start = tokio::time::Instant::now() + protocol::HEARTBEAT_INTERVAL;
+                Some(tokio::time::interval_at(start, protocol::HEARTBEAT_INTERVAL))
+            }
+            Mode::Plain => None,
+        };
+
+        Client {
+            inner,
+            identity,
+            framing,
+            mode,
+            output,
+            rx,
+            scrollback: scrollback.into_iter(),
+            heartbeat,
+            state,
+        }
+    }
+
+    /// Create a new passthrough client connecting the running program to stdout/stdin.
+    pub async fn new_term(state: Arc<Mutex<Shared>>) -> Self {
+        let framing = state.lock().await.framing();
+        Client::new(ClientImpl::Term(TermClient::new(framing)), Identity::anonymous(ClientRef::Term, Role::Operator), Mode::Plain, QueueConfig::default(), Streams::ALL, state).await
+    }
+
+    /// Create a new client connected to a network stream.
+    pub async fn new_net(transport: Transport, mode: Mode, identity: Identity, queue: QueueConfig, streams: Streams, state: Arc<Mutex<Shared>>) -> Self {
+        let framing = state.lock().await.framing();
+        let peer = identity.client;
+        let inner = match (transport, mode) {
+            (Transport::Raw(stream), Mode::Plain) => ClientImpl::Net(NetClient::new(stream, peer, framing).await),
+            (Transport::Raw(stream), Mode::Multiplexed) => ClientImpl::Mux(MuxClient::new(stream, peer)),
+            (Transport::WebSocket(ws), mode) => ClientImpl::Ws(WsClient::new(ws, peer, framing, mode)),
+            (Transport::Telnet(stream), _) => ClientImpl::Telnet(TelnetClient::new(stream, peer, framing)),
+        };
+
+        Client::new(inner, identity, mode, queue, streams, state).await
+    }
+
+    /// Start processing the client. This consumes the client after the connection to it has closed.
+    pub async fn process(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut notified = false;
+        let mut count = 0;
+
+        while let Some(result) = self.next().await {
+            count += 1;
+            if count % MESSAGE_BATCH == 0 {
+                let () = tokio::task::yield_now().await;
+            }
+
+            match result {
+                Ok(Message::ToProgram(_)) | Ok(Message::Command(_)) if self.identity.role == Role::Observer => {
+                    if !notified {
+                        notified = true;
+                        // errors are handled when sending the next output
+                        let _ = self.inner.send(&Event::Error(READ_ONLY_NOTICE.to_owned()), self.framing).await;
+                    }
+                }
+                Ok(Message::ToProgram(msg)) => {
+                    let outbox = {
+                        let mut state = self.state.lock().await;
+                        state.write_to_stdin(&msg, &self.identity);
+                        state.take_outbox()
+                    };
+
+                    publish(&self.output, outbox).await;
+                }
+                Ok(Message::Command(command)) => {
+                    let outbox = {
+                        let mut state = self.state.lock().await;
+                        state.run_command(command, &self.identity);
+                        state.take_outbox()
+                    };
+
+                    publish(&self.output, outbox).await;
+                }
+                // observers don't get a say in the program's window size
+                Ok(Message::Resize(_)) if self.identity.role == Role::Observer => {}
+                Ok(Message::Resize(size)) => {
+                    let mut state = self.state.lock().await;
+
+                    state.resize(size, &self.identity);
+                }
+                Ok(Message::ToClient(event)) => {
+                    match self.inner.send(&event, self.framing).await {
+                        Ok(_) => {}
+                        // don't print broken pipe/connection reset errors because those will always
+                        // occur on disconnection before the stream knows it has to close
+                        Err(e) if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset => {}
+                        Err(e) => Err(e)?,
+                    }
+                }
+                Ok(Message::Heartbeat) => {
+                    // errors are handled when sending the next output
+                    let _ = self.inner.send(&Event::Heartbeat, self.framing).await;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "an error occurred while processing messages for {}; error = {:?}",
+                        self.identity, e
+                    );
+
+                    // the connection can't be read from reliably after an error
+                    if self.mode == Mode::Multiplexed {
+                        let _ = self.inner.send(&Event::Error(e.to_string()), self.framing).await;
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        if self.rx.overflowed() {
+            eprintln!("disconnected {} because it couldn't keep up with output", self.identity);
+        }
+
+        Ok(())
+    }
+}
+
+impl Stream for Client {
+    type Item = io::Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(v) = self.scrollback.next() {
+            return Poll::Ready(Some(Ok(Message::ToClient(v))));
+        }
+
+        match Pin::new(&mut self.rx).poll_next(cx) {
+            Poll::Ready(Some(v)) => return Poll::Ready(Some(Ok(Message::ToClient(v)))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        if let Some(heartbeat) = &mut self.heartbeat {
+            if heartbeat.poll_tick(cx).is_ready() {
+                return Poll::Ready(Some(Ok(Message::Heartbeat)));
+            }
+        }
+
+        loop {
+            let result: Option<_> = futures::ready!(Pin::new(&mut self.inner).poll_next(cx));
+
+            return Poll::Ready(match result {
+                Some(Ok(Request::Input(buf))) => Some(Ok(Message::ToProgram(buf))),
+                Some(Ok(Request::Signal(signal))) => Some(Ok(Message::Command(Command::Signal(signal)))),
+                Some(Ok(Request::Resize(size))) => Some(Ok(Message::Resize(size))),
+                Some(Ok(Request::Heartbeat)) => continue,
+                Some(Err(e)) => Some(Err(e)),
+                None => None,
+            });
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let addr = self.inner.get_ref();
+        let state = self.state.clone();
+        let output = self.output.clone();
+        tokio::spawn(async move {
+            let outbox = {
+                let mut state = state.lock().await;
+                state.remove_client(addr);
+                state.take_outbox()
+            };
+
+            publish(&output, outbox).await;
+        });
+    }
+}
+
+/// Send the messages the shared state collected while handling something for a client.
+async fn publish(output: &Broadcast<Event>, outbox: Outbox) {
+    for (event, audience) in outbox {
+        output.send(event, audience).await;
+    }
+}
+
+impl ClientImpl {
+    fn get_ref(&self) -> ClientRef {
+        match self {
+            ClientImpl::Term(_) => ClientRef::Term,
+            ClientImpl::Net(c) => c.get_ref(),
+            ClientImpl::Mux(c) => c.get_ref(),
+            ClientImpl::Ws(c) => c.get_ref(),
+            ClientImpl::Telnet(c) => c.get_ref(),
+        }
+    }
+
+    async fn send(&mut self, event: &Event, framing: Framing) -> io::Result<()> {
+        match self {
+            ClientImpl::Mux(c) => c.send(event).await,
+            ClientImpl::Ws(c) => c.send(event).await,
+            ClientImpl::Term(c) => match event.render(framing) {
+                Some(data) => c.send_line(&data).await,
+                None => Ok(()),
+            },
+            ClientImpl::Net(c) => match event.render(framing) {
+                Some(data) => c.send_line(&data).await,
+                None => Ok(()),
+            },
+            ClientImpl::Telnet(c) => match event.render(framing) {
+                Some(data) => c.send_line(&data).await,
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+impl Stream for ClientImpl {
+    type Item = io::Result<Request>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            ClientImpl::Term(c) => Pin::new(c).poll_next(cx),
+            ClientImpl::Net(c) => Pin::new(c).poll_next(cx).map_ok(Request::Input),
+            ClientImpl::Mux(c) => Pin::new(c).poll_next(cx),
+            ClientImpl::Ws(c) => Pin::new(c).poll_next(cx),
+            ClientImpl::Telnet(c) => Pin::new(c).poll_next(cx),
+        }
+    }
+}
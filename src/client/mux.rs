@@ -0,0 +1,42 @@
+use std::io;
+
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::macros::support::{Pin, Poll};
+use tokio::stream::Stream;
+use tokio_util::codec::Framed;
+
+use crate::client::{ClientRef, Connection};
+use crate::client::event::Event;
+use crate::client::protocol::{ProtocolCodec, Request};
+
+/// A client connected through a raw connection using the multiplexed protocol.
+pub struct MuxClient {
+    frames: Framed<Box<dyn Connection>, ProtocolCodec>,
+    peer: ClientRef,
+}
+
+impl MuxClient {
+    pub fn new(stream: Box<dyn Connection>, peer: ClientRef) -> Self {
+        MuxClient {
+            frames: Framed::new(stream, ProtocolCodec),
+            peer,
+        }
+    }
+
+    pub fn get_ref(&self) -> ClientRef {
+        self.peer
+    }
+
+    pub async fn send(&mut self, event: &Event) -> io::Result<()> {
+        self.frames.send(event).await
+    }
+}
+
+impl Stream for MuxClient {
+    type Item = io::Result<Request>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.frames).poll_next(cx)
+    }
+}
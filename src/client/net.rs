@@ -0,0 +1,44 @@
+use std::io;
+
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::macros::support::{Pin, Poll};
+use tokio::stream::Stream;
+use tokio_util::codec::Framed;
+
+use crate::client::{ClientRef, Connection};
+use crate::framing::{FrameCodec, Framing};
+
+pub struct NetClient {
+    frames: Framed<Box<dyn Connection>, FrameCodec>,
+    peer: ClientRef,
+}
+
+impl NetClient {
+    pub async fn new(
+        stream: Box<dyn Connection>,
+        peer: ClientRef,
+        framing: Framing,
+    ) -> NetClient {
+        NetClient {
+            frames: Framed::new(stream, FrameCodec::new(framing)),
+            peer,
+        }
+    }
+
+    pub fn get_ref(&self) -> ClientRef {
+        self.peer
+    }
+
+    pub async fn send_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.frames.send(line).await
+    }
+}
+
+impl Stream for NetClient {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.frames).poll_next(cx)
+    }
+}
@@ -0,0 +1,422 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::handshake::server::{Request as WsRequest, Response as WsResponse};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::asyncreadwrap::Prefixed;
+use crate::client::{Connection, Role};
+use crate::client::broadcast::Source;
+use crate::client::control::Signal;
+use crate::client::event::Event;
+use crate::framing::MAX_FRAME_LENGTH;
+use crate::pty::WindowSize;
+
+/// Sent by a client at the start of a raw connection to ask for the multiplexed protocol, and
+/// sent back by conlink to confirm it.
+pub const PREAMBLE: &[u8] = b"\0conlink-mux/1\n";
+
+/// The WebSocket subprotocol to ask for the multiplexed protocol with.
+pub const WS_SUBPROTOCOL: &str = "conlink-mux.v1";
+
+/// How long to wait for a client on a raw connection to ask for the multiplexed protocol.
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How often clients using the multiplexed protocol are sent a heartbeat.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+const HEADER_LENGTH: usize = 5;
+
+// frame kinds
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const INPUT: u8 = 3;
+const EXIT: u8 = 4;
+const JOIN: u8 = 5;
+const LEAVE: u8 = 6;
+const NOTICE: u8 = 7;
+const ERROR: u8 = 8;
+const SKIPPED: u8 = 9;
+const HEARTBEAT: u8 = 10;
+const SIGNAL: u8 = 11;
+const RESIZE: u8 = 12;
+
+/// How events are sent to a client.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Mode {
+    /// Output and messages from conlink are sent as plain data in the configured framing, other
+    /// events are left out.
+    Plain,
+
+    /// Every event is sent as a typed frame.
+    Multiplexed,
+}
+
+/// A frame received from a client using the multiplexed protocol.
+#[derive(Debug)]
+pub enum Request {
+    /// A message to send to the program.
+    Input(Vec<u8>),
+
+    /// A signal to send to the program.
+    Signal(Signal),
+
+    /// The size of the client's terminal changed.
+    Resize(WindowSize),
+
+    Heartbeat,
+}
+
+/// Encodes events as frames of the multiplexed protocol, and decodes the frames clients send.
+///
+/// Each frame consists of a byte giving its kind, the length of the payload as a 32 bit
+/// big-endian integer, and the payload. Strings inside a payload are preceded by their length as a
+/// 16 bit big-endian integer.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ProtocolCodec;
+
+impl Decoder for ProtocolCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        if buf.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let kind = buf[0];
+        let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+
+        if len > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too long", len)));
+        }
+
+        if buf.len() < HEADER_LENGTH + len {
+            buf.reserve(HEADER_LENGTH + len - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(HEADER_LENGTH);
+        let payload = buf.split_to(len);
+
+        match kind {
+            INPUT => Ok(Some(Request::Input(payload.to_vec()))),
+            HEARTBEAT => Ok(Some(Request::Heartbeat)),
+            SIGNAL => {
+                let group = match payload.first() {
+                    Some(0) => false,
+                    Some(1) => true,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid signal target")),
+                };
+
+                match Signal::parse(&payload[1..], group) {
+                    Ok(signal) => Ok(Some(Request::Signal(signal))),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                }
+            }
+            RESIZE => match payload[..] {
+                [c1, c0, r1, r0] => {
+                    let size = WindowSize {
+                        cols: u16::from_be_bytes([c1, c0]),
+                        rows: u16::from_be_bytes([r1, r0]),
+                    };
+
+                    if size.cols == 0 || size.rows == 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "window size of 0"));
+                    }
+
+                    Ok(Some(Request::Resize(size)))
+                }
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid window size")),
+            },
+            kind => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame of kind {}", kind))),
+        }
+    }
+}
+
+impl Encoder<&Event> for ProtocolCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, event: &Event, buf: &mut BytesMut) -> io::Result<()> {
+        let start = buf.len();
+        buf.reserve(HEADER_LENGTH);
+        buf.put_u8(0);
+        buf.put_u32(0);
+
+        let kind = match event {
+            Event::Output { source, data, .. } => {
+                buf.extend_from_slice(data);
+
+                match source {
+                    Source::Stdout => STDOUT,
+                    Source::Stderr => STDERR,
+                }
+            }
+            Event::Input { from, data } => {
+                put_str(buf, &from.to_string());
+                buf.extend_from_slice(data);
+                INPUT
+            }
+            Event::Exit(status) => {
+                match status.code() {
+                    Some(code) => {
+                        buf.put_u8(0);
+                        buf.put_i32(code);
+                    }
+                    None => {
+                        buf.put_u8(1);
+                        buf.put_i32(status.signal().unwrap_or(0));
+                    }
+                }
+
+                EXIT
+            }
+            Event::Join(identity) => {
+                buf.put_u8(match identity.role {
+                    Role::Observer => 0,
+                    Role::Operator => 1,
+                });
+                put_str(buf, identity.user.as_deref().unwrap_or(""));
+                put_str(buf, &identity.client.to_string());
+                JOIN
+            }
+            Event::Leave(client) => {
+                put_str(buf, &client.to_string());
+                LEAVE
+            }
+            Event::Notice(text) => {
+                buf.extend_from_slice(text.as_bytes());
+                NOTICE
+            }
+            Event::Error(text) => {
+                buf.extend_from_slice(text.as_bytes());
+                ERROR
+            }
+            Event::Skipped { messages, bytes } => {
+                buf.put_u64(*messages as u64);
+                buf.put_u64(*bytes as u64);
+                SKIPPED
+            }
+            Event::Heartbeat => HEARTBEAT,
+        };
+
+        let len = (buf.len() - start - HEADER_LENGTH) as u32;
+        buf[start] = kind;
+        buf[start + 1..start + HEADER_LENGTH].copy_from_slice(&len.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    let len = s.len().min(u16::MAX as usize);
+    buf.put_u16(len as u16);
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Encode a single event, for transports that frame messages themselves.
+pub fn encode(event: &Event) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    // encoding into memory can't fail
+    let _ = ProtocolCodec.encode(event, &mut buf);
+    buf.to_vec()
+}
+
+/// Decode the frames in a message received through a transport that frames messages itself.
+pub fn decode_requests(data: &[u8]) -> io::Result<Vec<Request>> {
+    let mut buf = BytesMut::from(data);
+    let mut requests = Vec::new();
+
+    while let Some(request) = ProtocolCodec.decode(&mut buf)? {
+        requests.push(request);
+    }
+
+    if !buf.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message ends in the middle of a frame"));
+    }
+
+    Ok(requests)
+}
+
+/// Check whether a client on a raw connection asks for the multiplexed protocol by sending the
+/// preamble, and confirm it if so. Clients not sending anything within a short time, or sending
+/// anything else, get the plain protocol, with the data read so far still available to read from
+/// the returned stream.
+pub async fn negotiate(mut stream: Box<dyn Connection>) -> io::Result<(Box<dyn Connection>, Mode)> {
+    let deadline = tokio::time::Instant::now() + NEGOTIATE_TIMEOUT;
+    let mut buf = Vec::new();
+
+    // never read past the preamble, so that nothing after it is consumed
+    while buf.len() < PREAMBLE.len() && PREAMBLE.starts_with(&buf) {
+        let mut chunk = [0; PREAMBLE.len()];
+        let len = match tokio::time::timeout_at(deadline, stream.read(&mut chunk[..PREAMBLE.len() - buf.len()])).await {
+            Ok(r) => r?,
+            Err(_) => break,
+        };
+
+        if len == 0 {
+            break;
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    if buf == PREAMBLE {
+        stream.write_all(PREAMBLE).await?;
+        return Ok((stream, Mode::Multiplexed));
+    }
+
+    if buf.is_empty() {
+        Ok((stream, Mode::Plain))
+    } else {
+        Ok((Box::new(Prefixed::new(buf, stream)), Mode::Plain))
+    }
+}
+
+/// Accept the multiplexed protocol during a WebSocket handshake if the client asks for it.
+pub fn negotiate_ws(request: &WsRequest, mut response: WsResponse) -> (WsResponse, Mode) {
+    let requested = request.headers().get_all("Sec-WebSocket-Protocol").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim() == WS_SUBPROTOCOL);
+
+    if !requested {
+        return (response, Mode::Plain);
+    }
+
+    response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(WS_SUBPROTOCOL));
+    (response, Mode::Multiplexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UnixStream;
+
+    use super::*;
+    use crate::client::{ClientRef, Identity};
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn decode_requests() {
+        let mut data = frame(INPUT, b"hello");
+        data.extend(frame(HEARTBEAT, b""));
+        data.extend(frame(SIGNAL, b"\x01INT"));
+        data.extend(frame(RESIZE, &[0, 80, 0, 24]));
+        data.extend(frame(INPUT, b""));
+
+        let requests = super::decode_requests(&data).unwrap();
+        assert_eq!(requests.len(), 5);
+        assert!(matches!(&requests[0], Request::Input(data) if data == b"hello"));
+        assert!(matches!(requests[1], Request::Heartbeat));
+        assert!(matches!(requests[2], Request::Signal(Signal { number: libc::SIGINT, group: true })));
+        assert!(matches!(requests[3], Request::Resize(WindowSize { cols: 80, rows: 24 })));
+        assert!(matches!(&requests[4], Request::Input(data) if data.is_empty()));
+    }
+
+    #[test]
+    fn decode_partial() {
+        let data = frame(INPUT, b"hello");
+        let mut buf = BytesMut::from(&data[..3]);
+        assert!(ProtocolCodec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&data[3..]);
+        assert!(matches!(ProtocolCodec.decode(&mut buf).unwrap(), Some(Request::Input(_))));
+        assert!(buf.is_empty());
+
+        assert!(super::decode_requests(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let invalid = [
+            frame(SIGNAL, b""),
+            frame(SIGNAL, b"\x02INT"),
+            frame(SIGNAL, b"\x00NOPE"),
+            frame(RESIZE, b""),
+            frame(RESIZE, &[0, 80, 0]),
+            frame(RESIZE, &[0, 0, 0, 24]),
+            frame(STDOUT, b"output"),
+            frame(0, b""),
+        ];
+
+        for data in &invalid {
+            let err = super::decode_requests(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let mut data = vec![INPUT];
+        data.extend_from_slice(&(MAX_FRAME_LENGTH as u32 + 1).to_be_bytes());
+        assert!(super::decode_requests(&data).is_err());
+    }
+
+    #[test]
+    fn encode_events() {
+        let addr: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let client = ClientRef::Net(addr);
+
+        let output = Event::Output { source: Source::Stderr, data: b"oops".to_vec(), labeled: Some(b"err: oops".to_vec()) };
+        assert_eq!(encode(&output), frame(STDERR, b"oops"));
+
+        let input = Event::Input { from: client, data: b"ls".to_vec() };
+        assert_eq!(encode(&input), frame(INPUT, b"\0\x0e127.0.0.1:7000ls"));
+
+        let mut identity = Identity::anonymous(client, Role::Operator);
+        identity.user = Some("alice".into());
+        assert_eq!(encode(&Event::Join(identity)), frame(JOIN, b"\x01\0\x05alice\0\x0e127.0.0.1:7000"));
+
+        assert_eq!(encode(&Event::Exit(ExitStatusExt::from_raw(3 << 8))), frame(EXIT, &[0, 0, 0, 0, 3]));
+        assert_eq!(encode(&Event::Exit(ExitStatusExt::from_raw(libc::SIGKILL))), frame(EXIT, &[1, 0, 0, 0, libc::SIGKILL as u8]));
+
+        let skipped = Event::Skipped { messages: 2, bytes: 300 };
+        assert_eq!(encode(&skipped), frame(SKIPPED, &[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 1, 44]));
+
+        assert_eq!(encode(&Event::Heartbeat), frame(HEARTBEAT, b""));
+    }
+
+    #[tokio::test]
+    async fn negotiate_multiplexed() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(PREAMBLE).await.unwrap();
+        theirs.write_all(b"rest").await.unwrap();
+
+        let (mut stream, mode) = negotiate(Box::new(ours)).await.unwrap();
+        assert_eq!(mode, Mode::Multiplexed);
+
+        let mut buf = [0; PREAMBLE.len()];
+        theirs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], PREAMBLE);
+
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"rest");
+    }
+
+    #[tokio::test]
+    async fn negotiate_plain() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(b"\0conlink-other\n").await.unwrap();
+        drop(theirs);
+
+        let (mut stream, mode) = negotiate(Box::new(ours)).await.unwrap();
+        assert_eq!(mode, Mode::Plain);
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"\0conlink-other\n");
+    }
+}
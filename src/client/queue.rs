@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+/// What to do with a client that doesn't read output as fast as the program produces it.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SlowClientPolicy {
+    /// Wait for the client to catch up, holding up output for every other client.
+    Block,
+
+    /// Discard output the client has no room for, telling it how much was skipped.
+    Drop,
+
+    /// Disconnect the client.
+    Disconnect,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(SlowClientPolicy::Block),
+            "drop" => Ok(SlowClientPolicy::Drop),
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            _ => Err(format!("invalid slow client policy '{}', expected block, drop or disconnect", s)),
+        }
+    }
+}
+
+/// Bounds for the output waiting to be sent to a client, and what happens when they are hit.
+#[derive(Debug, Copy, Clone)]
+pub struct QueueConfig {
+    pub policy: SlowClientPolicy,
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            policy: SlowClientPolicy::Block,
+            max_messages: 4096,
+            max_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Parse a size in bytes, optionally followed by one of the suffixes `K` or `M` for kibibytes or
+/// mebibytes.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, mult) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    num.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Output that can be queued for a client.
+pub trait Output: Send + Sync + 'static {
+    /// The size counted against the queue's and the scrollback's byte limits.
+    fn size(&self) -> usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("4M"), Ok(4 * 1024 * 1024));
+
+        for s in &["", "M", "4B", "-1", "1.5K", "99999999999999999999M"] {
+            assert!(parse_size(s).is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!("block".parse(), Ok(SlowClientPolicy::Block));
+        assert_eq!("drop".parse(), Ok(SlowClientPolicy::Drop));
+        assert_eq!("disconnect".parse(), Ok(SlowClientPolicy::Disconnect));
+        assert!("Block".parse::<SlowClientPolicy>().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::client::broadcast::{Source, Streams};
+use crate::client::queue::Output;
+
+/// How much output to keep for replaying to newly connected clients.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ScrollbackLimit {
+    /// Keep at most this many messages, which are lines unless a different framing is used.
+    Lines(usize),
+
+    /// Keep at most this many bytes of output.
+    Bytes(usize),
+}
+
+impl FromStr for ScrollbackLimit {
+    type Err = String;
+
+    /// Parse a scrollback limit. A plain number is a line count, a number followed by one of the
+    /// suffixes `B`, `K` or `M` is a size in bytes, kibibytes or mebibytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid scrollback limit '{}'", s);
+
+        let (num, mult) = match s.chars().last() {
+            Some('b') | Some('B') => (&s[..s.len() - 1], Some(1)),
+            Some('k') | Some('K') => (&s[..s.len() - 1], Some(1024)),
+            Some('m') | Some('M') => (&s[..s.len() - 1], Some(1024 * 1024)),
+            _ => (s, None),
+        };
+
+        let num: usize = num.parse().map_err(|_| err())?;
+
+        match mult {
+            None => Ok(ScrollbackLimit::Lines(num)),
+            Some(mult) => num.checked_mul(mult).map(ScrollbackLimit::Bytes).ok_or_else(err),
+        }
+    }
+}
+
+/// A bounded buffer of the most recent program output. Entries are shared with the output sent to
+/// clients, so keeping them doesn't copy any data.
+pub struct Scrollback<T> {
+    entries: VecDeque<(Source, Arc<T>)>,
+    size: usize,
+    limit: ScrollbackLimit,
+}
+
+impl<T> Scrollback<T>
+    where T: Output {
+    pub fn new(limit: ScrollbackLimit) -> Self {
+        Scrollback {
+            entries: VecDeque::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    /// Add an entry, discarding the oldest entries if the limit is exceeded.
+    pub fn push(&mut self, source: Source, entry: Arc<T>) {
+        match self.limit {
+            ScrollbackLimit::Lines(0) | ScrollbackLimit::Bytes(0) => return,
+            _ => {}
+        }
+
+        self.size += entry.size();
+        self.entries.push_back((source, entry));
+
+        while self.exceeded() {
+            match self.entries.pop_front() {
+                Some((_, e)) => self.size -= e.size(),
+                None => break,
+            }
+        }
+    }
+
+    /// Get the buffered output from the given streams, oldest entry first.
+    pub fn snapshot(&self, streams: Streams) -> Vec<Arc<T>> {
+        self.entries.iter()
+            .filter(|(source, _)| streams.includes(*source))
+            .map(|(_, e)| e.clone())
+            .collect()
+    }
+
+    fn exceeded(&self) -> bool {
+        match self.limit {
+            ScrollbackLimit::Lines(n) => self.entries.len() > n,
+            ScrollbackLimit::Bytes(n) => self.size > n,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct Entry(&'static str);
+
+    impl Output for Entry {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    fn contents(scrollback: &Scrollback<Entry>, streams: Streams) -> Vec<&'static str> {
+        scrollback.snapshot(streams).iter().map(|e| e.0).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("100".parse(), Ok(ScrollbackLimit::Lines(100)));
+        assert_eq!("0".parse(), Ok(ScrollbackLimit::Lines(0)));
+        assert_eq!("512B".parse(), Ok(ScrollbackLimit::Bytes(512)));
+        assert_eq!("4k".parse(), Ok(ScrollbackLimit::Bytes(4096)));
+        assert_eq!("2M".parse(), Ok(ScrollbackLimit::Bytes(2 * 1024 * 1024)));
+
+        for s in &["", "K", "-1", "1.5M", "10G", "99999999999999999999M"] {
+            assert!(s.parse::<ScrollbackLimit>().is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn line_limit() {
+        let mut scrollback = Scrollback::new(ScrollbackLimit::Lines(2));
+
+        for line in &["one", "two", "three"] {
+            scrollback.push(Source::Stdout, Arc::new(Entry(line)));
+        }
+
+        assert_eq!(contents(&scrollback, Streams::ALL), vec!["two", "three"]);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut scrollback = Scrollback::new(ScrollbackLimit::Bytes(8));
+
+        for line in &["one", "two", "three"] {
+            scrollback.push(Source::Stdout, Arc::new(Entry(line)));
+        }
+
+        assert_eq!(contents(&scrollback, Streams::ALL), vec!["two", "three"]);
+
+        // an entry larger than the limit isn't kept at all
+        scrollback.push(Source::Stdout, Arc::new(Entry("too long to keep")));
+        assert_eq!(contents(&scrollback, Streams::ALL), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut scrollback = Scrollback::new(ScrollbackLimit::Lines(0));
+        scrollback.push(Source::Stdout, Arc::new(Entry("one")));
+        assert_eq!(contents(&scrollback, Streams::ALL), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn snapshot_streams() {
+        let mut scrollback = Scrollback::new(ScrollbackLimit::Lines(10));
+        scrollback.push(Source::Stdout, Arc::new(Entry("out")));
+        scrollback.push(Source::Stderr, Arc::new(Entry("err")));
+
+        assert_eq!(contents(&scrollback, Streams::ALL), vec!["out", "err"]);
+        assert_eq!(contents(&scrollback, Streams { stdout: true, stderr: false }), vec!["out"]);
+        assert_eq!(contents(&scrollback, Streams { stdout: false, stderr: true }), vec!["err"]);
+    }
+}
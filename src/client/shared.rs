@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::client::{ClientRef, Identity};
+use crate::client::broadcast::{Audience, Broadcast};
+use crate::client::control::{Command, Input, LineState, Piece, Signal};
+use crate::client::event::Event;
+use crate::client::floor::{Floor, Notices};
+use crate::client::window::{ResizePolicy, Window};
+use crate::cmd::{self, ChildInput};
+use crate::framing::Framing;
+use crate::logfile::OutputLog;
+use crate::pty::{Terminal, WindowSize};
+use crate::record::Recorder;
+
+/// The most input kept for a program that is about to start, or waiting for the program to read
+/// it.
+const PENDING_LIMIT: usize = 1024;
+
+/// Messages for clients, to be sent once the shared state has been released.
+pub type Outbox = Vec<(Event, Audience)>;
+
+/// The state shared between all tasks.
+///
+/// Messages to clients aren't sent while the state is locked, because sending may wait for a
+/// client to catch up with output, and that client may itself be waiting for the state. They are
+/// collected instead, and taken with [`Shared::take_outbox`] to be sent after releasing it.
+pub struct Shared {
+    program: Program,
+    framing: Framing,
+    echo: bool,
+    /// Set if only the client holding the input lock may send input.
+    floor: Option<Floor>,
+    /// Whether clients may send signals to the program.
+    signals: bool,
+    /// Where each client is in the line it is sending, for finding commands in stream framing.
+    lines: HashMap<ClientRef, LineState>,
+    /// The window sizes of clients, deciding the size of the program's terminal.
+    window: Window,
+    output: Arc<Broadcast<Event>>,
+    /// Messages for clients not yet sent.
+    outbox: Outbox,
+    /// Records the session, if it is being recorded.
+    recorder: Option<Recorder>,
+    /// Writes the program's output to a file, if configured.
+    log: Option<OutputLog>,
+    /// The number of connected network clients.
+    clients: usize,
+    presence: watch::Sender<usize>,
+    presence_rx: watch::Receiver<usize>,
+}
+
+/// Whether the program can currently receive input.
+enum Program {
+    Running {
+        /// Input waiting to be written to the program by its input task.
+        stdin: mpsc::Sender<Vec<u8>>,
+        /// Stops the input task when dropped.
+        _stop: oneshot::Sender<()>,
+        pid: u32,
+        terminal: Option<Terminal>,
+    },
+
+    /// The program is about to be started. Input is kept until it is.
+    Starting(Vec<Vec<u8>>),
+
+    Stopped,
+}
+
+impl Shared {
+    /// Create a new shared state.
+    pub fn new(framing: Framing, output: Arc<Broadcast<Event>>, echo: bool, floor: bool, signals: bool, resize: ResizePolicy) -> Self {
+        let (presence, presence_rx) = watch::channel(0);
+
+        Shared {
+            program: Program::Starting(Vec::new()),
+            framing,
+            echo,
+            floor: if floor { Some(Floor::new()) } else { None },
+            signals,
+            lines: HashMap::new(),
+            window: Window::new(resize),
+            output,
+            outbox: Vec::new(),
+            recorder: None,
+            log: None,
+            clients: 0,
+            presence,
+            presence_rx,
+        }
+    }
+
+    /// Record the session, including the program's output, input sent to it and changes to the
+    /// size of its terminal.
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Write the program's output to a file.
+    pub fn with_log(mut self, log: Option<OutputLog>) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Pass client input to a newly started program, starting with the input sent while it was
+    /// starting. Its terminal, if any, gets the size chosen from clients' sizes.
+    ///
+    /// Input is written by a task of its own, so that a program that doesn't read its input
+    /// doesn't keep the state locked.
+    pub fn attach(&mut self, stdin: ChildInput, pid: u32, terminal: Option<Terminal>) {
+        let pending = match mem::replace(&mut self.program, Program::Stopped) {
+            Program::Starting(pending) => pending,
+            _ => Vec::new(),
+        };
+
+        let (tx, rx) = mpsc::channel(PENDING_LIMIT);
+        let (stop, stopped) = oneshot::channel();
+        cmd::process_input(stdin, self.framing, pending, rx, stopped);
+
+        self.program = Program::Running { stdin: tx, _stop: stop, pid, terminal };
+        self.window.reset();
+        self.apply_size();
+    }
+
+    /// Stop passing client input on after the program has exited. If it is going to be started
+    /// again, input is kept for it in the meantime.
+    pub fn detach(&mut self, restarting: bool) {
+        self.program = if restarting { Program::Starting(Vec::new()) } else { Program::Stopped };
+    }
+
+    /// Whether the program should be started now, when it is only run while clients are
+    /// connected.
+    pub fn wants_start(&self) -> bool {
+        matches!(self.program, Program::Starting(_)) && self.clients > 0
+    }
+
+    /// Get notified whenever the number of connected network clients changes.
+    pub fn presence(&self) -> watch::Receiver<usize> {
+        self.presence_rx.clone()
+    }
+
+    /// Announce a newly connected client.
+    pub fn add_client(&mut self, identity: &Identity) {
+        if identity.client != ClientRef::Term {
+            self.clients += 1;
+
+            // the first client after the program stopped gets it started again
+            if self.clients == 1 && matches!(self.program, Program::Stopped) {
+                self.program = Program::Starting(Vec::new());
+            }
+
+            let _ = self.presence.broadcast(self.clients);
+        }
+
+        self.send(Event::Join(identity.clone()), Audience::All);
+    }
+
+    /// Handle a client disconnecting, passing on the input lock if it held it.
+    pub fn remove_client(&mut self, client: ClientRef) {
+        if let Some(floor) = &mut self.floor {
+            let notices = floor.remove(client);
+            self.notify(notices);
+        }
+
+        self.lines.remove(&client);
+        self.window.remove(client);
+        self.apply_size();
+
+        if client != ClientRef::Term {
+            self.clients -= 1;
+            let _ = self.presence.broadcast(self.clients);
+        }
+
+        self.send(Event::Leave(client), Audience::All);
+    }
+
+    pub fn framing(&self) -> Framing { self.framing }
+
+    pub fn output(&self) -> &Arc<Broadcast<Event>> { &self.output }
+
+    pub fn recorder(&self) -> Option<Recorder> { self.recorder.clone() }
+
+    pub fn log(&self) -> Option<OutputLog> { self.log.clone() }
+
+    /// Queue a message for the program's input.
+    pub fn write_to_stdin(&mut self, msg: &[u8], from: &Identity) {
+        let msg = match self.check_input(msg, from) {
+            Some(msg) => msg,
+            None => return,
+        };
+
+        match &mut self.program {
+            Program::Running { stdin, .. } => match stdin.try_send(msg.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    let notice = Event::Error("the program isn't reading its input, input is dropped".to_owned());
+                    self.send(notice, Audience::Only(from.client));
+                    return;
+                }
+                // the input task has already said why it stopped
+                Err(TrySendError::Closed(_)) => return,
+            },
+            Program::Starting(pending) if pending.len() < PENDING_LIMIT => pending.push(msg.clone()),
+            _ => {
+                let notice = Event::Error("the program isn't running, input is ignored".to_owned());
+                self.send(notice, Audience::Only(from.client));
+                return;
+            }
+        }
+
+        if let Some(recorder) = &self.recorder {
+            recorder.input(from.client, &msg);
+        }
+
+        if self.echo {
+            self.send(Event::Input { from: from.client, data: msg }, Audience::Except(from.client));
+        }
+    }
+
+    /// Take note of the size of a client's terminal, resizing the program's terminal if the
+    /// resize policy says so.
+    pub fn resize(&mut self, size: WindowSize, from: &Identity) {
+        self.window.report(from.client, size);
+        self.apply_size();
+    }
+
+    /// Change the size of the program's terminal, if it is running on one and the size chosen
+    /// from clients' sizes has changed.
+    fn apply_size(&mut self) {
+        let holder = self.floor.as_ref().and_then(Floor::holder);
+
+        if let Some(size) = self.window.update(holder) {
+            if let Program::Running { terminal: Some(terminal), .. } = &self.program {
+                match terminal.resize(size) {
+                    Ok(_) => {
+                        if let Some(recorder) = &self.recorder {
+                            recorder.resize(size);
+                        }
+                    }
+                    Err(e) => eprintln!("failed to resize terminal to {}x{}: {}", size.cols, size.rows, e),
+                }
+            }
+        }
+    }
+
+    /// Carry out a command sent by a client.
+    pub fn run_command(&mut self, command: Command, from: &Identity) {
+        let notices = match (command, &mut self.floor) {
+            (Command::Signal(signal), _) => return self.signal(signal, from),
+            (Command::Lock, Some(floor)) => floor.lock(from),
+            (Command::Unlock, Some(floor)) => floor.unlock(from),
+            (Command::Status, Some(floor)) => floor.status(from),
+            (_, None) => vec![(Audience::Only(from.client), "there is no input lock".to_owned())],
+        };
+
+        self.notify(notices);
+
+        // the input lock may have changed hands
+        self.apply_size();
+    }
+
+    /// Send a signal to the program for a client, telling every client about it.
+    fn signal(&mut self, signal: Signal, from: &Identity) {
+        if !self.signals {
+            let notice = Event::Error("sending signals to the program isn't allowed".to_owned());
+            self.send(notice, Audience::Only(from.client));
+            return;
+        }
+
+        // signals are input too, as far as the input lock is concerned
+        if let Err(notices) = self.may_send(from) {
+            self.notify(notices);
+            return;
+        }
+
+        let pid = match self.program {
+            Program::Running { pid, .. } => pid as libc::pid_t,
+            _ => {
+                let notice = Event::Error(format!("the program isn't running, {} wasn't sent", signal));
+                self.send(notice, Audience::Only(from.client));
+                return;
+            }
+        };
+
+        // the program is the leader of its process group
+        let (result, target) = if signal.group {
+            (unsafe { libc::killpg(pid, signal.number) }, "the program's process group")
+        } else {
+            (unsafe { libc::kill(pid, signal.number) }, "the program")
+        };
+
+        if result == -1 {
+            let e = io::Error::last_os_error();
+            eprintln!("failed to send {} to process {} for {}: {}", signal, pid, from, e);
+            self.send(Event::Error(format!("failed to send {}: {}", signal, e)), Audience::Only(from.client));
+            return;
+        }
+
+        eprintln!("{} sent {} to {} ({})", from, signal, target, pid);
+        self.send(Event::Notice(format!("{} sent {} to {}", from, signal, target)), Audience::All);
+    }
+
+    /// Check whether the client may currently send input, if only the lock holder may.
+    fn may_send(&mut self, from: &Identity) -> Result<(), Notices> {
+        match &mut self.floor {
+            Some(floor) => floor.check(from),
+            None => Ok(()),
+        }
+    }
+
+    /// Take the messages for clients collected while the state was locked.
+    pub fn take_outbox(&mut self) -> Outbox {
+        mem::take(&mut self.outbox)
+    }
+
+    /// Queue a message for clients.
+    fn send(&mut self, event: Event, audience: Audience) {
+        self.outbox.push((event, audience));
+    }
+
+    /// Send messages from conlink itself to clients.
+    fn notify(&mut self, notices: Notices) {
+        for (audience, msg) in notices {
+            self.send(Event::Notice(msg), audience);
+        }
+    }
+
+    /// Handle commands in input if any are enabled, and check whether the client may send
+    /// input if only the lock holder may. Returns the input to pass to the program, if any. In
+    /// stream framing, input may contain several lines or only part of one, so lines are put
+    /// together across messages to check them for commands.
+    fn check_input(&mut self, msg: &[u8], from: &Identity) -> Option<Vec<u8>> {
+        if self.floor.is_none() && !self.signals {
+            return Some(msg.to_owned());
+        }
+
+        let pieces = if self.framing.is_stream() {
+            self.lines.entry(from.client).or_insert_with(LineState::new).feed(msg)
+        } else {
+            vec![Piece::Line(msg.to_owned())]
+        };
+
+        let mut result = None;
+
+        for piece in &pieces {
+            let input = match piece {
+                Piece::Line(line) => Input::parse(line),
+                Piece::Data(data) => Input::Data(data),
+            };
+
+            let data = match input {
+                Input::Command(command) if self.accepts(command) => {
+                    self.run_command(command, from);
+                    continue;
+                }
+                Input::Invalid(e) if self.signals => {
+                    self.send(Event::Error(e), Audience::Only(from.client));
+                    continue;
+                }
+                // commands that aren't enabled are passed on like any other input
+                Input::Command(_) | Input::Invalid(_) => piece.as_bytes(),
+                Input::Data(data) => data,
+            };
+
+            match self.may_send(from) {
+                Ok(_) => result.get_or_insert_with(Vec::new).extend_from_slice(data),
+                Err(notices) => self.notify(notices),
+            }
+        }
+
+        result
+    }
+
+    /// Whether a command is enabled, as opposed to being passed on to the program.
+    fn accepts(&self, command: Command) -> bool {
+        match command {
+            Command::Signal(_) => self.signals,
+            Command::Lock | Command::Unlock | Command::Status => self.floor.is_some(),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::io;
+
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::macros::support::{Pin, Poll};
+use tokio::stream::Stream;
+use tokio_util::codec::Framed;
+
+use crate::client::ClientRef;
+use crate::client::control::Signal;
+use crate::client::protocol::Request;
+use crate::framing::{FrameCodec, Framing};
+use crate::telnet::{TelnetEvent, TelnetStream};
+
+/// A client connected using the telnet protocol. Interrupting the program is the same as sending
+/// it SIGINT, and the client's window size is passed on to the program's terminal.
+pub struct TelnetClient {
+    frames: Framed<Box<TelnetStream>, FrameCodec>,
+    peer: ClientRef,
+}
+
+impl TelnetClient {
+    pub fn new(stream: Box<TelnetStream>, peer: ClientRef, framing: Framing) -> Self {
+        TelnetClient {
+            frames: Framed::new(stream, FrameCodec::new(framing)),
+            peer,
+        }
+    }
+
+    pub fn get_ref(&self) -> ClientRef {
+        self.peer
+    }
+
+    pub async fn send_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.frames.send(line).await
+    }
+
+    fn next_event(&mut self) -> Option<Request> {
+        self.frames.get_mut().next_event().map(|event| match event {
+            TelnetEvent::Interrupt => Request::Signal(Signal { number: libc::SIGINT, group: false }),
+            TelnetEvent::WindowSize(size) => Request::Resize(size),
+        })
+    }
+}
+
+impl Stream for TelnetClient {
+    type Item = io::Result<Request>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(request) = self.next_event() {
+            return Poll::Ready(Some(Ok(request)));
+        }
+
+        match Pin::new(&mut self.frames).poll_next(cx) {
+            Poll::Ready(result) => Poll::Ready(result.map(|r| r.map(Request::Input))),
+            // reading may have turned up commands without any data
+            Poll::Pending => match self.next_event() {
+                Some(request) => Poll::Ready(Some(Ok(request))),
+                None => Poll::Pending,
+            },
+        }
+    }
+}
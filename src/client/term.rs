@@ -0,0 +1,73 @@
+use std::io;
+
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::io::{Stdin, Stdout};
+use tokio::macros::support::{Pin, Poll};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::stream::Stream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::client::protocol::Request;
+use crate::framing::{FrameCodec, Framing};
+use crate::pty::WindowSize;
+
+/// The client on conlink's own stdin and stdout. If stdout is a terminal, its size is passed on
+/// to the program's terminal whenever it changes.
+pub struct TermClient {
+    stdin: FramedRead<Stdin, FrameCodec>,
+    stdout: FramedWrite<Stdout, FrameCodec>,
+    /// Notified when the size of the terminal changes.
+    winch: Option<Signal>,
+    /// The size of the terminal, if it hasn't been passed on yet.
+    size: Option<WindowSize>,
+}
+
+impl TermClient {
+    pub fn new(framing: Framing) -> Self {
+        let size = terminal_size();
+        let winch = match size {
+            Some(_) => signal(SignalKind::window_change())
+                .map_err(|e| eprintln!("failed to watch for terminal size changes: {}", e))
+                .ok(),
+            None => None,
+        };
+
+        TermClient {
+            stdin: FramedRead::new(tokio::io::stdin(), FrameCodec::new(framing)),
+            stdout: FramedWrite::new(tokio::io::stdout(), FrameCodec::new(framing)),
+            winch,
+            size,
+        }
+    }
+
+    pub async fn send_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.stdout.send(line).await
+    }
+}
+
+impl Stream for TermClient {
+    type Item = io::Result<Request>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(winch) = &mut this.winch {
+            // several changes in a row only need the latest size
+            while let Poll::Ready(Some(())) = Pin::new(&mut *winch).poll_next(cx) {
+                this.size = terminal_size().or(this.size);
+            }
+        }
+
+        if let Some(size) = this.size.take() {
+            return Poll::Ready(Some(Ok(Request::Resize(size))));
+        }
+
+        Pin::new(&mut this.stdin).poll_next(cx).map_ok(Request::Input)
+    }
+}
+
+/// Get the size of the terminal on stdout, if it is one and knows its size.
+fn terminal_size() -> Option<WindowSize> {
+    WindowSize::of_terminal(libc::STDOUT_FILENO).ok().filter(|size| size.cols > 0 && size.rows > 0)
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::client::ClientRef;
+use crate::pty::WindowSize;
+
+/// Which client's window size the program's terminal gets when several clients report one.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ResizePolicy {
+    /// The smallest size in each direction, so that every client can show the whole terminal.
+    Smallest,
+
+    /// The size most recently reported by any client.
+    Latest,
+
+    /// The size of the client holding the input lock.
+    LockHolder,
+}
+
+impl FromStr for ResizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smallest" => Ok(ResizePolicy::Smallest),
+            "latest" => Ok(ResizePolicy::Latest),
+            "lock-holder" => Ok(ResizePolicy::LockHolder),
+            _ => Err(format!("invalid resize policy '{}', expected smallest, latest or lock-holder", s)),
+        }
+    }
+}
+
+/// Tracks the window sizes clients report, to decide the size of the program's terminal.
+#[derive(Debug)]
+pub struct Window {
+    policy: ResizePolicy,
+    sizes: HashMap<ClientRef, WindowSize>,
+    latest: Option<WindowSize>,
+    /// The size last given to the program's terminal.
+    applied: Option<WindowSize>,
+}
+
+impl Window {
+    pub fn new(policy: ResizePolicy) -> Self {
+        Window {
+            policy,
+            sizes: HashMap::new(),
+            latest: None,
+            applied: None,
+        }
+    }
+
+    pub fn report(&mut self, client: ClientRef, size: WindowSize) {
+        self.sizes.insert(client, size);
+        self.latest = Some(size);
+    }
+
+    pub fn remove(&mut self, client: ClientRef) {
+        self.sizes.remove(&client);
+    }
+
+    /// Forget the size given to the terminal, for a newly started program.
+    pub fn reset(&mut self) {
+        self.applied = None;
+    }
+
+    /// Get the size the terminal should change to, if it should change.
+    pub fn update(&mut self, holder: Option<ClientRef>) -> Option<WindowSize> {
+        let wanted = match self.policy {
+            ResizePolicy::Latest => self.latest,
+            ResizePolicy::Smallest => self.sizes.values().copied().reduce(|a, b| WindowSize {
+                cols: a.cols.min(b.cols),
+                rows: a.rows.min(b.rows),
+            }),
+            ResizePolicy::LockHolder => holder.and_then(|h| self.sizes.get(&h).copied()),
+        };
+
+        match wanted {
+            Some(size) if self.applied != Some(size) => {
+                self.applied = Some(size);
+                Some(size)
+            }
+            _ => None,
+        }
+    }
+}
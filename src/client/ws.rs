@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::io;
+
+use futures::SinkExt;
+use futures::task::Context;
+use tokio::macros::support::{Pin, Poll};
+use tokio::stream::Stream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::client::{ClientRef, Connection};
+use crate::client::event::Event;
+use crate::client::protocol::{self, Mode, Request};
+use crate::framing::Framing;
+use crate::pty::WindowSize;
+
+/// A client connected through a WebSocket. Each message of output is sent in its own WebSocket
+/// message, as binary data in raw framing and as text otherwise. With the multiplexed protocol,
+/// every WebSocket message is a binary message containing one frame.
+///
+/// Without the multiplexed protocol, a message consisting of nothing but the sequence asking a
+/// terminal to change its size, `ESC [ 8 ; <rows> ; <cols> t`, gives the size of the client's
+/// terminal instead of being input.
+pub struct WsClient {
+    ws: Box<WebSocketStream<Box<dyn Connection>>>,
+    peer: ClientRef,
+    framing: Framing,
+    mode: Mode,
+    /// Requests received but not yet passed on, for messages containing more than one record or
+    /// frame.
+    pending: VecDeque<Request>,
+}
+
+impl WsClient {
+    pub fn new(ws: Box<WebSocketStream<Box<dyn Connection>>>, peer: ClientRef, framing: Framing, mode: Mode) -> Self {
+        WsClient {
+            ws,
+            peer,
+            framing,
+            mode,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> ClientRef {
+        self.peer
+    }
+
+    pub async fn send(&mut self, event: &Event) -> io::Result<()> {
+        let msg = match self.mode {
+            Mode::Multiplexed => Message::Binary(protocol::encode(event)),
+            Mode::Plain => match event.render(self.framing) {
+                Some(data) if self.framing.is_stream() => Message::Binary(data.into_owned()),
+                Some(data) => Message::Text(String::from_utf8_lossy(&data).into_owned()),
+                None => return Ok(()),
+            },
+        };
+
+        self.ws.send(msg).await.map_err(ws_error)
+    }
+}
+
+impl Stream for WsClient {
+    type Item = io::Result<Request>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(request) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(request)));
+            }
+
+            let data = match futures::ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => data,
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(None),
+                // pings are answered by the WebSocket implementation
+                Some(Ok(_)) => continue,
+                Some(Err(WsError::ConnectionClosed)) | Some(Err(WsError::AlreadyClosed)) => return Poll::Ready(None),
+                Some(Err(e)) => return Poll::Ready(Some(Err(ws_error(e)))),
+            };
+
+            match self.mode {
+                Mode::Plain => match parse_resize(&data) {
+                    Some(size) => self.pending.push_back(Request::Resize(size)),
+                    None => {
+                        let records = self.framing.split(data);
+                        self.pending.extend(records.into_iter().map(Request::Input));
+                    }
+                },
+                Mode::Multiplexed => match protocol::decode_requests(&data) {
+                    Ok(requests) => self.pending.extend(requests),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                },
+            }
+        }
+    }
+}
+
+/// Parse a message asking to change the size of the terminal.
+pub fn parse_resize(data: &[u8]) -> Option<WindowSize> {
+    let params = data.strip_prefix(b"\x1b[8;")?.strip_suffix(b"t")?;
+    let params = std::str::from_utf8(params).ok()?;
+    let mut iter = params.splitn(2, ';');
+    let rows = iter.next()?.parse().ok()?;
+    let cols = iter.next()?.parse().ok()?;
+
+    if cols == 0 || rows == 0 {
+        return None;
+    }
+
+    Some(WindowSize { cols, rows })
+}
+
+fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_messages() {
+        assert_eq!(parse_resize(b"\x1b[8;24;80t"), Some(WindowSize { cols: 80, rows: 24 }));
+        assert_eq!(parse_resize(b"\x1b[8;1;65535t"), Some(WindowSize { cols: 65535, rows: 1 }));
+    }
+
+    #[test]
+    fn other_messages() {
+        for data in &[&b""[..], b"\x1b[8;24;80", b"[8;24;80t", b"\x1b[8;24t", b"\x1b[8;24;80;1t", b"\x1b[8;0;80t", b"\x1b[8;24;65536t", b"\x1b[8;-1;80t", b"ls\n"] {
+            assert_eq!(parse_resize(data), None, "{:?}", data);
+        }
+    }
+}
@@ -1,100 +1,253 @@
-use std::fmt::Debug;
-use std::ops::Deref;
+use std::pin::Pin;
 use std::process::Stdio;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 
+use futures::SinkExt;
 use tokio::prelude::*;
 use tokio::process::{Child, Command};
-use tokio::stream::StreamExt;
-use tokio::sync::Mutex;
-use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
-
-use async_trait::async_trait;
-
-use crate::asyncreadwrap::StreamWrapper;
-use crate::client::Shared;
-
-pub fn start_command(command: &[String]) -> io::Result<Child> {
-    Command::new(command.first().unwrap())
-        .args(&command[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+use tokio::stream::{self as stream, Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::client::broadcast::{Broadcast, Source};
+use crate::client::event::Event;
+use crate::framing::{FrameCodec, Framing};
+use crate::logfile::OutputLog;
+use crate::pty::{make_controlling_terminal, Pty, PtyConfig, Terminal};
+use crate::record::Recorder;
+
+/// How many messages of output are passed on before letting other tasks run. A program writing
+/// output continuously would otherwise keep clients from ever running.
+const OUTPUT_BATCH: usize = 64;
+
+pub type ChildInput = Box<dyn AsyncWrite + Send + Unpin>;
+
+pub type ChildOutput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The standard streams of the running program.
+pub struct ChildIo {
+    pub stdin: ChildInput,
+    pub stdout: ChildOutput,
+    /// The program's error output. This is `None` if it is merged into `stdout`, like when
+    /// running on a pseudo-terminal.
+    pub stderr: Option<ChildOutput>,
+    /// The terminal the program is running on, if any.
+    pub terminal: Option<Terminal>,
 }
 
-/// Start a task reading the program's output, sending it to the connected clients.
-pub fn process_stdout<T, W, S, D>(stdout: T, state: Arc<Mutex<S>>, rw: W)
-    where T: AsyncRead + Unpin + Send + 'static,
-          W: ReadWrapper<T, Data=D> + 'static,
-          S: Shared<Data=<D as Deref>::Target> + Send + 'static,
-          D: Deref + Send + Sync,
-          <D as Deref>::Target: Sync {
-    tokio::spawn(async move {
-        let mut reader = rw.create_reader(stdout);
-        while let Some(line) = rw.read(&mut reader).await {
-            match line {
-                Ok(line) => {
-                    state.lock().await.write_output(line.deref()).await;
-                }
-                Err(e) => {
-                    eprintln!("failed to read from program output: {:?}", e);
+pub fn start_command(command: &[String], pty: Option<&PtyConfig>) -> io::Result<(Child, ChildIo)> {
+    let mut cmd = Command::new(command.first().unwrap());
+    cmd.args(&command[1..]);
+
+    match pty {
+        None => {
+            // in its own process group, the program can be signalled together with everything it
+            // starts, and conlink isn't signalled along with it
+            unsafe { cmd.pre_exec(|| if libc::setpgid(0, 0) == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }); }
+
+            let mut child = cmd
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+
+            let io = ChildIo {
+                stdin: Box::new(child.stdin.take().unwrap()),
+                stdout: Box::new(child.stdout.take().unwrap()),
+                stderr: Some(Box::new(child.stderr.take().unwrap())),
+                terminal: None,
+            };
+
+            Ok((child, io))
+        }
+        Some(config) => {
+            let pty = Pty::open(config.size)?;
+
+            cmd.env("TERM", &config.term)
+                .stdin(pty.slave_stdio()?)
+                .stdout(pty.slave_stdio()?)
+                .stderr(pty.slave_stdio()?);
+
+            unsafe { cmd.pre_exec(make_controlling_terminal); }
+
+            let child = cmd.spawn()?;
+            let terminal = pty.terminal()?;
+            let (stdout, stdin) = tokio::io::split(pty.into_master()?);
+
+            let io = ChildIo {
+                stdin: Box::new(stdin),
+                stdout: Box::new(stdout),
+                stderr: None,
+                terminal: Some(terminal),
+            };
+
+            Ok((child, io))
+        }
+    }
+}
+
+/// How the program's error output is marked to tell it apart from normal output.
+#[derive(Debug, Default, Clone)]
+pub struct StderrLabel {
+    /// Text to put in front of every line.
+    pub prefix: Option<String>,
+    /// Whether to color the output red.
+    pub color: bool,
+}
+
+impl StderrLabel {
+    fn is_empty(&self) -> bool {
+        self.prefix.is_none() && !self.color
+    }
+
+    /// Label a message of error output. In raw framing, messages don't line up with lines, so
+    /// `line_start` keeps track of whether the previous message ended a line.
+    fn apply(&self, msg: &[u8], framing: Framing, line_start: &mut bool) -> Vec<u8> {
+        let mut result = Vec::with_capacity(msg.len() + 16);
+
+        if self.color {
+            result.extend_from_slice(b"\x1b[31m");
+        }
+
+        match &self.prefix {
+            Some(prefix) if framing.is_stream() => {
+                for (i, line) in msg.split_inclusive(|&b| b == b'\n').enumerate() {
+                    if i > 0 || *line_start {
+                        result.extend_from_slice(prefix.as_bytes());
+                    }
+
+                    result.extend_from_slice(line);
                 }
             }
+            Some(prefix) => {
+                result.extend_from_slice(prefix.as_bytes());
+                result.extend_from_slice(msg);
+            }
+            None => result.extend_from_slice(msg),
         }
-    });
+
+        if self.color {
+            result.extend_from_slice(b"\x1b[0m");
+        }
+
+        *line_start = msg.last() == Some(&b'\n');
+        result
+    }
 }
 
-#[async_trait]
-pub trait ReadWrapper<T>: Send + Sync
-    where T: AsyncRead + Unpin + Send + 'static {
-    type Reader: Send;
-    type Data;
-    type Error: Debug + Send;
+/// Start a task reading the program's output, sending it to the connected clients. Both streams
+/// are read in the same task, so that output is passed on in the order it becomes available. The
+/// task ends once both streams are closed. The output is also recorded and logged to a file, if
+/// configured.
+pub fn process_output(stdout: ChildOutput, stderr: Option<ChildOutput>, output: Arc<Broadcast<Event>>, framing: Framing, label: StderrLabel, recorder: Option<Recorder>, log: Option<OutputLog>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let stdout = FramedRead::new(stdout, FrameCodec::new(framing)).map(|r| (Source::Stdout, r));
+        let mut reader: Pin<Box<dyn Stream<Item=_> + Send>> = match stderr {
+            Some(stderr) => {
+                let stderr = FramedRead::new(stderr, FrameCodec::new(framing)).map(|r| (Source::Stderr, r));
+                Box::pin(PreferFirst { first: Some(stdout), second: Some(stderr) })
+            }
+            None => Box::pin(stdout),
+        };
 
-    fn create_reader(&self, stdout: T) -> Self::Reader;
+        let mut line_start = true;
+        let mut count = 0;
 
-    async fn read(&self, reader: &mut Self::Reader) -> Option<Result<Self::Data, Self::Error>>;
-}
+        while let Some((source, msg)) = reader.next().await {
+            match msg {
+                Ok(msg) => {
+                    let labeled = match source {
+                        Source::Stderr if !label.is_empty() => Some(label.apply(&msg, framing, &mut line_start)),
+                        _ => None,
+                    };
 
-#[derive(Copy, Clone)]
-pub struct StrReadWrapper;
+                    let shown = labeled.as_deref().unwrap_or(&msg);
 
-#[async_trait]
-impl<T> ReadWrapper<T> for StrReadWrapper
-    where T: AsyncRead + Unpin + Send + 'static {
-    type Reader = FramedRead<T, LinesCodec>;
-    type Data = String;
-    type Error = LinesCodecError;
+                    if let Some(recorder) = &recorder {
+                        recorder.output(shown);
+                    }
 
-    fn create_reader(&self, stdout: T) -> Self::Reader {
-        FramedRead::new(stdout, LinesCodec::new())
-    }
+                    if let Some(log) = &log {
+                        log.output(shown);
+                    }
 
-    async fn read(&self, reader: &mut Self::Reader) -> Option<Result<Self::Data, LinesCodecError>> {
-        match reader.next().await {
-            Some(Ok(v)) => Some(Ok(v)),
-            Some(Err(e)) => Some(Err(e)),
-            None => None,
+                    output.publish_output(Event::Output { source, data: msg, labeled }, source).await;
+
+                    count += 1;
+                    if count % OUTPUT_BATCH == 0 {
+                        let () = tokio::task::yield_now().await;
+                    }
+                }
+                Err(e) => {
+                    // the rest of the output can't be framed reliably after an error
+                    eprintln!("failed to read from program output: {:?}", e);
+                    break;
+                }
+            }
         }
-    }
+    })
 }
 
-#[derive(Copy, Clone)]
-pub struct BinReadWrapper;
+/// Start a task writing client input to the program, starting with the given input kept from
+/// before it started. Stops when the input channel is closed, or when `stop` is dropped even if
+/// the program isn't reading its input.
+pub fn process_input(stdin: ChildInput, framing: Framing, pending: Vec<Vec<u8>>, input: mpsc::Receiver<Vec<u8>>, stop: oneshot::Receiver<()>) {
+    tokio::spawn(async move {
+        let mut stdin = FramedWrite::new(stdin, FrameCodec::new(framing));
+        let mut input = stream::iter(pending).chain(input);
+
+        let write = async {
+            while let Some(msg) = input.next().await {
+                if let Err(e) = stdin.send(&msg[..]).await {
+                    eprintln!("failed to pass to program: {:?}", e);
+                    break;
+                }
+            }
+        };
 
-#[async_trait]
-impl<T> ReadWrapper<T> for BinReadWrapper
-    where T: AsyncRead + Unpin + Send + 'static {
-    type Reader = T;
-    type Data = Vec<u8>;
-    type Error = io::Error;
+        tokio::select! {
+            _ = write => {}
+            _ = stop => {}
+        }
+    });
+}
 
-    fn create_reader(&self, stdout: T) -> Self::Reader {
-        stdout
-    }
+/// Merges two streams, always taking from the first one when both are ready. Output the program
+/// wrote to stdout before stderr is usually read in that order this way, since both are ready by
+/// the time they are polled.
+struct PreferFirst<A, B> {
+    first: Option<A>,
+    second: Option<B>,
+}
 
-    async fn read(&self, reader: &mut Self::Reader) -> Option<Result<Self::Data, io::Error>> {
-        StreamWrapper::of(reader).next().await
+impl<A, B, T> Stream for PreferFirst<A, B>
+    where A: Stream<Item=T> + Unpin,
+          B: Stream<Item=T> + Unpin {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(first) = &mut self.first {
+            match Pin::new(first).poll_next(cx) {
+                Poll::Ready(Some(item)) => return Poll::Ready(Some(item)),
+                Poll::Ready(None) => self.first = None,
+                Poll::Pending => {}
+            }
+        }
+
+        if let Some(second) = &mut self.second {
+            match Pin::new(second).poll_next(cx) {
+                Poll::Ready(Some(item)) => return Poll::Ready(Some(item)),
+                Poll::Ready(None) => self.second = None,
+                Poll::Pending => {}
+            }
+        }
+
+        if self.first.is_none() && self.second.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
     }
-}
\ No newline at end of file
+}
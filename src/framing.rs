@@ -0,0 +1,365 @@
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The largest frame accepted in length-prefixed framing and the multiplexed protocol.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// How program input and output are split into messages.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Framing {
+    /// Lines terminated by `\n`, with an optional `\r` before it.
+    Lines,
+
+    /// Data is passed on in whatever pieces it is read in.
+    Raw,
+
+    /// Records terminated by a NUL byte.
+    Nul,
+
+    /// Records terminated by the given byte.
+    Delimiter(u8),
+
+    /// Frames preceded by their length as a 32 bit big-endian integer.
+    LengthPrefixed,
+
+    /// Chunks of a fixed number of bytes. A shorter chunk is only produced at the end of the
+    /// stream.
+    Fixed(usize),
+}
+
+impl Framing {
+    /// Whether messages are arbitrary pieces of a byte stream, rather than separate records.
+    pub fn is_stream(self) -> bool {
+        self == Framing::Raw
+    }
+
+    /// The byte terminating each record, if any.
+    fn delimiter(self) -> Option<u8> {
+        match self {
+            Framing::Lines => Some(b'\n'),
+            Framing::Nul => Some(0),
+            Framing::Delimiter(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Split data received as a single piece, such as a WebSocket message, into records.
+    pub fn split(self, data: Vec<u8>) -> Vec<Vec<u8>> {
+        match self.delimiter() {
+            Some(delim) => {
+                let mut records: Vec<_> = data.split(|&b| b == delim).map(|r| self.trim(r).to_vec()).collect();
+
+                // data ending with a delimiter doesn't have an empty record after it
+                if records.len() > 1 && records.last().is_some_and(|r| r.is_empty()) {
+                    records.pop();
+                }
+
+                records
+            }
+            None => vec![data],
+        }
+    }
+
+    /// Format a message from conlink itself.
+    pub fn notice(self, text: &str) -> Vec<u8> {
+        if self.is_stream() {
+            format!("conlink: {}\n", text).into_bytes()
+        } else {
+            format!("conlink: {}", text).into_bytes()
+        }
+    }
+
+    /// The marker sent in place of output a client was too slow to receive.
+    pub fn skipped(self, messages: usize, bytes: usize) -> Vec<u8> {
+        match self {
+            Framing::Raw => format!("\r\nconlink: {} bytes skipped\r\n", bytes).into_bytes(),
+            Framing::Lines => self.notice(&format!("{} lines skipped", messages)),
+            _ => self.notice(&format!("{} messages skipped", messages)),
+        }
+    }
+
+    fn trim(self, record: &[u8]) -> &[u8] {
+        match (self, record.split_last()) {
+            (Framing::Lines, Some((b'\r', rest))) => rest,
+            _ => record,
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    /// Parse a framing: `lines`, `raw`, `nul`, `length`, `delim:<byte>` or `fixed:<size>`. The
+    /// delimiter byte is given as a single ASCII character, or in hexadecimal like `0x1e`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid framing '{}'", s);
+
+        match s {
+            "lines" => return Ok(Framing::Lines),
+            "raw" => return Ok(Framing::Raw),
+            "nul" => return Ok(Framing::Nul),
+            "length" => return Ok(Framing::LengthPrefixed),
+            _ => {}
+        }
+
+        if let Some(delim) = s.strip_prefix("delim:") {
+            let byte = match delim.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16).map_err(|_| err())?,
+                None if delim.len() == 1 && delim.is_ascii() => delim.as_bytes()[0],
+                None => return Err(err()),
+            };
+
+            return Ok(Framing::Delimiter(byte));
+        }
+
+        if let Some(size) = s.strip_prefix("fixed:") {
+            return match size.parse() {
+                Ok(size) if size > 0 => Ok(Framing::Fixed(size)),
+                _ => Err(err()),
+            };
+        }
+
+        Err(err())
+    }
+}
+
+impl Display for Framing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::Lines => write!(f, "lines"),
+            Framing::Raw => write!(f, "raw"),
+            Framing::Nul => write!(f, "nul"),
+            Framing::Delimiter(b) => write!(f, "delim:0x{:02x}", b),
+            Framing::LengthPrefixed => write!(f, "length"),
+            Framing::Fixed(size) => write!(f, "fixed:{}", size),
+        }
+    }
+}
+
+/// Splits a byte stream into messages and writes messages to one, according to a framing.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameCodec {
+    framing: Framing,
+    /// How far the buffer has been searched for a delimiter.
+    searched: usize,
+}
+
+impl FrameCodec {
+    pub fn new(framing: Framing) -> Self {
+        FrameCodec { framing, searched: 0 }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        if let Some(delim) = self.framing.delimiter() {
+            return Ok(match buf[self.searched..].iter().position(|&b| b == delim) {
+                Some(pos) => {
+                    let record = buf.split_to(self.searched + pos + 1);
+                    self.searched = 0;
+                    Some(self.framing.trim(&record[..record.len() - 1]).to_vec())
+                }
+                None => {
+                    self.searched = buf.len();
+                    None
+                }
+            });
+        }
+
+        match self.framing {
+            Framing::Fixed(size) if buf.len() >= size => Ok(Some(buf.split_to(size).to_vec())),
+            Framing::LengthPrefixed if buf.len() >= 4 => {
+                let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+
+                if len > MAX_FRAME_LENGTH {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too long", len)));
+                }
+
+                if buf.len() < 4 + len {
+                    buf.reserve(4 + len - buf.len());
+                    return Ok(None);
+                }
+
+                buf.advance(4);
+                Ok(Some(buf.split_to(len).to_vec()))
+            }
+            Framing::Raw if !buf.is_empty() => Ok(Some(buf.split().to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        if let Some(frame) = self.decode(buf)? {
+            return Ok(Some(frame));
+        }
+
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        match self.framing {
+            // a truncated length-prefixed frame can't be interpreted
+            Framing::LengthPrefixed => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended in the middle of a frame")),
+            // pass on the unterminated end of the stream
+            _ => {
+                self.searched = 0;
+                let rest = buf.split();
+                Ok(Some(self.framing.trim(&rest).to_vec()))
+            }
+        }
+    }
+}
+
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: &[u8], buf: &mut BytesMut) -> io::Result<()> {
+        match self.framing {
+            Framing::LengthPrefixed => {
+                if frame.len() > MAX_FRAME_LENGTH {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes is too long", frame.len())));
+                }
+
+                buf.reserve(4 + frame.len());
+                buf.put_u32(frame.len() as u32);
+                buf.put_slice(frame);
+            }
+            framing => {
+                buf.reserve(frame.len() + 1);
+                buf.put_slice(frame);
+
+                if let Some(delim) = framing.delimiter() {
+                    buf.put_u8(delim);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(framing: Framing, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut codec = FrameCodec::new(framing);
+        let mut buf = BytesMut::from(data);
+        let mut frames = Vec::new();
+
+        while let Some(frame) = codec.decode(&mut buf)? {
+            frames.push(frame);
+        }
+
+        while let Some(frame) = codec.decode_eof(&mut buf)? {
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("lines".parse(), Ok(Framing::Lines));
+        assert_eq!("raw".parse(), Ok(Framing::Raw));
+        assert_eq!("nul".parse(), Ok(Framing::Nul));
+        assert_eq!("length".parse(), Ok(Framing::LengthPrefixed));
+        assert_eq!("delim:;".parse(), Ok(Framing::Delimiter(b';')));
+        assert_eq!("delim:0x1e".parse(), Ok(Framing::Delimiter(0x1e)));
+        assert_eq!("fixed:16".parse(), Ok(Framing::Fixed(16)));
+
+        for s in &["", "line", "delim:", "delim:ab", "delim:0x100", "delim:ä", "fixed:0", "fixed:x"] {
+            assert!(s.parse::<Framing>().is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for framing in &[Framing::Lines, Framing::Raw, Framing::Nul, Framing::Delimiter(b'|'), Framing::LengthPrefixed, Framing::Fixed(3)] {
+            assert_eq!(framing.to_string().parse(), Ok(*framing));
+        }
+    }
+
+    #[test]
+    fn decode_lines() {
+        let frames = decode_all(Framing::Lines, b"one\r\ntwo\n\nthree\r").unwrap();
+        assert_eq!(frames, vec![b"one".to_vec(), b"two".to_vec(), b"".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn decode_in_pieces() {
+        let mut codec = FrameCodec::new(Framing::Delimiter(b';'));
+        let mut buf = BytesMut::from(&b"ab"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"c;d");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), Some(b"d".to_vec()));
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_fixed() {
+        let frames = decode_all(Framing::Fixed(3), b"abcdefgh").unwrap();
+        assert_eq!(frames, vec![b"abc".to_vec(), b"def".to_vec(), b"gh".to_vec()]);
+    }
+
+    #[test]
+    fn decode_length_prefixed() {
+        let frames = decode_all(Framing::LengthPrefixed, b"\0\0\0\x02ab\0\0\0\0\0\0\0\x01c").unwrap();
+        assert_eq!(frames, vec![b"ab".to_vec(), b"".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn decode_truncated_length_prefix() {
+        let err = decode_all(Framing::LengthPrefixed, b"\0\0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = decode_all(Framing::LengthPrefixed, b"\0\0\0\x05abc").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let len = (MAX_FRAME_LENGTH as u32 + 1).to_be_bytes();
+        let err = FrameCodec::new(Framing::LengthPrefixed).decode(&mut BytesMut::from(&len[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encode_decode() {
+        for framing in &[Framing::Lines, Framing::Nul, Framing::Delimiter(b'|'), Framing::LengthPrefixed] {
+            let mut codec = FrameCodec::new(*framing);
+            let mut buf = BytesMut::new();
+            codec.encode(b"first", &mut buf).unwrap();
+            codec.encode(b"", &mut buf).unwrap();
+            codec.encode(b"second", &mut buf).unwrap();
+
+            let frames = decode_all(*framing, &buf).unwrap();
+            assert_eq!(frames, vec![b"first".to_vec(), b"".to_vec(), b"second".to_vec()], "{}", framing);
+        }
+    }
+
+    #[test]
+    fn encode_raw() {
+        let mut buf = BytesMut::new();
+        FrameCodec::new(Framing::Raw).encode(b"abc", &mut buf).unwrap();
+        assert_eq!(&buf[..], b"abc");
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(Framing::split(Framing::Lines, b"a\r\nb\n".to_vec()), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(Framing::split(Framing::Lines, b"\n".to_vec()), vec![b"".to_vec()]);
+        assert_eq!(Framing::split(Framing::Raw, b"a\nb".to_vec()), vec![b"a\nb".to_vec()]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::handshake::server::Request as WsRequest;
+
+use crate::asyncreadwrap::Prefixed;
+use crate::client::Connection;
+
+const TERMINAL_PAGE: &str = include_str!("web/terminal.html");
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Get the web terminal page, set up for the given mode.
+pub fn terminal_page(binary: bool) -> String {
+    TERMINAL_PAGE.replace("{{BINARY}}", if binary { "true" } else { "false" })
+}
+
+/// Read an HTTP request from the stream. If it is a WebSocket upgrade request, return the stream
+/// for the WebSocket handshake to continue on, otherwise answer it with the given page.
+pub async fn serve(mut stream: Box<dyn Connection>, page: &str) -> io::Result<Option<Box<dyn Connection>>> {
+    let mut buf = Vec::new();
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+
+        let mut chunk = [0; 1024];
+        let len = stream.read(&mut chunk).await?;
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let upgrade = request.headers.iter()
+        .any(|h| h.name.eq_ignore_ascii_case("upgrade") && h.value.eq_ignore_ascii_case(b"websocket"));
+
+    if upgrade {
+        return Ok(Some(Box::new(Prefixed::new(buf, stream))));
+    }
+
+    let path = request.path.unwrap_or("/").split('?').next().unwrap();
+
+    let response = match (request.method, path) {
+        (Some("GET"), "/") | (Some("GET"), "/index.html") => response("200 OK", "text/html; charset=utf-8", page),
+        (Some("GET"), _) => response("404 Not Found", "text/plain", "not found\n"),
+        _ => response("405 Method Not Allowed", "text/plain", "method not allowed\n"),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(None)
+}
+
+/// Check whether a WebSocket handshake comes from a page that may connect, to keep pages on
+/// other sites from connecting with the access of whoever views them. Browsers always send the
+/// origin of the page; other clients don't, and are accepted. Pages are accepted if they were
+/// served from the host connected to, or their origin is one of the given ones.
+pub fn origin_allowed(request: &WsRequest, allowed: &[String]) -> bool {
+    let origin = match request.headers().get("Origin").map(|v| v.to_str()) {
+        Some(Ok(origin)) => origin,
+        Some(Err(_)) => return false,
+        None => return true,
+    };
+
+    if allowed.iter().any(|a| a.eq_ignore_ascii_case(origin)) {
+        return true;
+    }
+
+    let host = request.headers().get("Host").and_then(|v| v.to_str().ok());
+    let origin_host = origin.split("://").nth(1);
+
+    matches!((host, origin_host), (Some(host), Some(origin_host)) if host.eq_ignore_ascii_case(origin_host))
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    )
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future;
+use tokio::sync::{watch, Mutex};
+
+use crate::client::{Client, Identity, Transport};
+use crate::client::broadcast::{Audience, Broadcast, Streams};
+use crate::client::event::Event;
+use crate::client::protocol::Mode;
+use crate::client::queue::QueueConfig;
+use crate::client::scrollback::ScrollbackLimit;
+use crate::client::shared::Shared;
+use crate::client::window::ResizePolicy;
+use crate::cmd::StderrLabel;
+use crate::framing::Framing;
+use crate::pty::PtyConfig;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{self, RestartConfig, Supervisor};
+
+/// Runs a separate instance of the program for every client, like inetd.
+pub struct Instances {
+    command: Vec<String>,
+    pty: Option<PtyConfig>,
+    label: StderrLabel,
+    restart: RestartConfig,
+    framing: Framing,
+    /// Whether clients may send signals to their instance.
+    signals: bool,
+    shutdown: Shutdown,
+    /// The number of instances running.
+    running: std::sync::Mutex<usize>,
+    count: watch::Sender<usize>,
+    count_rx: watch::Receiver<usize>,
+}
+
+impl Instances {
+    pub fn new(command: &[String], pty: Option<PtyConfig>, label: StderrLabel, restart: RestartConfig, framing: Framing, signals: bool, shutdown: Shutdown) -> Arc<Self> {
+        let (count, count_rx) = watch::channel(0);
+
+        Arc::new(Instances {
+            command: command.to_vec(),
+            pty,
+            label,
+            restart,
+            framing,
+            signals,
+            shutdown,
+            running: std::sync::Mutex::new(0),
+            count,
+            count_rx,
+        })
+    }
+
+    /// Count an instance as started or stopped, for anything waiting for all of them to stop.
+    fn count_running(&self, started: bool) {
+        let mut running = self.running.lock().unwrap();
+
+        if started {
+            *running += 1;
+        } else {
+            *running -= 1;
+        }
+
+        let _ = self.count.broadcast(*running);
+    }
+
+    /// Wait until no instances are running anymore.
+    pub async fn wait_stopped(&self) {
+        let mut count = self.count_rx.clone();
+
+        while *count.borrow() > 0 {
+            if count.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Start an instance of the program connected only to the client, and run both until the
+    /// program exits or the client disconnects. The program is stopped if the client disconnects
+    /// first, and the connection is closed once the program's output has been sent if it exits
+    /// first.
+    pub async fn serve(&self, transport: Transport, mode: Mode, identity: Identity, queue: QueueConfig, streams: Streams) {
+        let output = Broadcast::new(ScrollbackLimit::Lines(0), |messages, bytes| Event::Skipped { messages, bytes });
+        let state = Arc::new(Mutex::new(Shared::new(self.framing, output.clone(), false, false, self.signals, ResizePolicy::Latest)));
+
+        // the program is stopped as soon as the client is gone
+        let supervisor = Supervisor::new(&self.command, self.pty.clone(), self.label.clone(), self.restart, Some(Duration::from_secs(0)), state.clone(), self.shutdown.clone())
+            .for_client(identity.clone());
+
+        // connect the client first, so that the program doesn't get stopped right away
+        let client = Client::new_net(transport, mode, identity.clone(), queue, streams, state).await;
+
+        let program = async {
+            match supervisor.run_once().await {
+                Ok(status) => output.send(Event::Notice(format!("process exited with {}", supervisor::describe(status))), Audience::All).await,
+                Err(e) => {
+                    eprintln!("failed to run process for {}: {:?}", identity, e);
+                    output.send(Event::Error(format!("failed to run the program: {}", e)), Audience::All).await;
+                }
+            }
+
+            output.close();
+        };
+
+        let client = async {
+            if let Err(e) = client.process().await {
+                eprintln!("error while processing network client: {:?}", e);
+            }
+        };
+
+        self.count_running(true);
+        future::join(program, client).await;
+        self.count_running(false);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::client::ClientRef;
+
+/// How many connections a single address may open in a period of time.
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    pub count: usize,
+    pub period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse a rate limit in the form `<count>/<seconds>`, e.g. `10/60`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.splitn(2, '/');
+        let count = iter.next().and_then(|v| v.parse().ok());
+        let period = iter.next().and_then(|v| v.parse().ok());
+
+        match (count, period) {
+            (Some(count), Some(period)) if count > 0 && period > 0 => Ok(RateLimit { count, period: Duration::from_secs(period) }),
+            _ => Err(format!("invalid rate limit '{}'", s)),
+        }
+    }
+}
+
+/// Limits on the number of connected clients.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Limits {
+    pub max_clients: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    pub rate: Option<RateLimit>,
+    /// The maximum number of instances of the program running at once, when running one for
+    /// each client.
+    pub max_instances: Option<usize>,
+}
+
+/// Why a connection was refused.
+#[derive(Debug, Copy, Clone)]
+pub enum Refusal {
+    TooManyClients,
+    TooManyFromAddress,
+    RateLimited,
+    TooManyInstances,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::TooManyClients => write!(f, "too many clients connected"),
+            Refusal::TooManyFromAddress => write!(f, "too many connections from your address"),
+            Refusal::RateLimited => write!(f, "too many connection attempts from your address, try again later"),
+            Refusal::TooManyInstances => write!(f, "too many instances of the program running"),
+        }
+    }
+}
+
+/// Keeps track of open connections, refusing new ones that would exceed the limits.
+pub struct ConnectionLimiter {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    instances: usize,
+    /// The times of the most recent connection attempts from each address, oldest first.
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+/// Counts as an open connection until dropped.
+pub struct Permit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+}
+
+/// Counts as a running instance of the program until dropped.
+pub struct InstancePermit {
+    limiter: Arc<ConnectionLimiter>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: Limits) -> Arc<Self> {
+        Arc::new(ConnectionLimiter {
+            limits,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Check whether a new connection from the peer is allowed, returning a permit to hold for
+    /// as long as the connection is open.
+    pub fn admit(self: &Arc<Self>, peer: ClientRef) -> Result<Permit, Refusal> {
+        let ip = match peer {
+            ClientRef::Net(addr) => Some(addr.ip()),
+            _ => None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.forget_stale_attempts(self.limits.rate);
+
+        if let (Some(ip), Some(rate)) = (ip, self.limits.rate) {
+            let now = Instant::now();
+            let attempts = state.attempts.entry(ip).or_default();
+
+            while attempts.front().is_some_and(|&t| now.duration_since(t) >= rate.period) {
+                attempts.pop_front();
+            }
+
+            // refused attempts count too, so that a peer retrying in a loop stays refused
+            let limited = attempts.len() >= rate.count;
+            attempts.push_back(now);
+
+            if attempts.len() > rate.count {
+                attempts.pop_front();
+            }
+
+            if limited {
+                return Err(Refusal::RateLimited);
+            }
+        }
+
+        if self.limits.max_clients.is_some_and(|max| state.total >= max) {
+            return Err(Refusal::TooManyClients);
+        }
+
+        if let (Some(ip), Some(max)) = (ip, self.limits.max_per_ip) {
+            if state.per_ip.get(&ip).is_some_and(|&n| n >= max) {
+                return Err(Refusal::TooManyFromAddress);
+            }
+        }
+
+        state.total += 1;
+
+        if let Some(ip) = ip {
+            *state.per_ip.entry(ip).or_default() += 1;
+        }
+
+        Ok(Permit { limiter: self.clone(), ip })
+    }
+
+    /// Check whether another instance of the program may be started, returning a permit to hold
+    /// for as long as it runs.
+    pub fn admit_instance(self: &Arc<Self>) -> Result<InstancePermit, Refusal> {
+        let mut state = self.state.lock().unwrap();
+
+        if self.limits.max_instances.is_some_and(|max| state.instances >= max) {
+            return Err(Refusal::TooManyInstances);
+        }
+
+        state.instances += 1;
+        Ok(InstancePermit { limiter: self.clone() })
+    }
+}
+
+impl State {
+    /// Drop the attempt history of addresses that haven't connected for a while, so that it
+    /// doesn't grow without bound.
+    fn forget_stale_attempts(&mut self, rate: Option<RateLimit>) {
+        if let Some(rate) = rate {
+            if self.attempts.len() > 1024 {
+                let now = Instant::now();
+                self.attempts.retain(|_, a| a.back().is_some_and(|&t| now.duration_since(t) < rate.period));
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.total -= 1;
+
+        if let Some(ip) = self.ip {
+            if let Some(n) = state.per_ip.get_mut(&ip) {
+                *n -= 1;
+
+                if *n == 0 {
+                    state.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for InstancePermit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().instances -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::client::UnixPeer;
+
+    fn peer(s: &str) -> ClientRef {
+        ClientRef::Net(s.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        let rate: RateLimit = "10/60".parse().unwrap();
+        assert_eq!(rate.count, 10);
+        assert_eq!(rate.period, Duration::from_secs(60));
+
+        for s in &["", "10", "10/", "/60", "0/60", "10/0", "a/b", "10/60/1"] {
+            assert!(s.parse::<RateLimit>().is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn max_clients() {
+        let limiter = ConnectionLimiter::new(Limits { max_clients: Some(2), ..Limits::default() });
+        let first = limiter.admit(peer("192.0.2.1:1000")).unwrap();
+        let _second = limiter.admit(peer("192.0.2.2:1000")).unwrap();
+        assert!(matches!(limiter.admit(peer("192.0.2.3:1000")), Err(Refusal::TooManyClients)));
+
+        drop(first);
+        assert!(limiter.admit(peer("192.0.2.3:1000")).is_ok());
+    }
+
+    #[test]
+    fn max_per_ip() {
+        let limiter = ConnectionLimiter::new(Limits { max_per_ip: Some(1), ..Limits::default() });
+        let first = limiter.admit(peer("192.0.2.1:1000")).unwrap();
+        assert!(matches!(limiter.admit(peer("192.0.2.1:1001")), Err(Refusal::TooManyFromAddress)));
+        assert!(limiter.admit(peer("192.0.2.2:1000")).is_ok());
+
+        drop(first);
+        assert!(limiter.admit(peer("192.0.2.1:1001")).is_ok());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let rate = RateLimit { count: 2, period: Duration::from_secs(3600) };
+        let limiter = ConnectionLimiter::new(Limits { rate: Some(rate), ..Limits::default() });
+
+        // permits are dropped right away, only the attempts count
+        assert!(limiter.admit(peer("192.0.2.1:1000")).is_ok());
+        assert!(limiter.admit(peer("192.0.2.1:1001")).is_ok());
+        assert!(matches!(limiter.admit(peer("192.0.2.1:1002")), Err(Refusal::RateLimited)));
+        assert!(matches!(limiter.admit(peer("192.0.2.1:1003")), Err(Refusal::RateLimited)));
+        assert!(limiter.admit(peer("192.0.2.2:1000")).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_expires() {
+        let rate = RateLimit { count: 1, period: Duration::from_millis(50) };
+        let limiter = ConnectionLimiter::new(Limits { rate: Some(rate), ..Limits::default() });
+
+        assert!(limiter.admit(peer("192.0.2.1:1000")).is_ok());
+        assert!(limiter.admit(peer("192.0.2.1:1001")).is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.admit(peer("192.0.2.1:1002")).is_ok());
+    }
+
+    #[test]
+    fn unix_peers_only_count_towards_the_total() {
+        let limits = Limits { max_clients: Some(2), max_per_ip: Some(1), rate: Some(RateLimit { count: 1, period: Duration::from_secs(3600) }), ..Limits::default() };
+        let limiter = ConnectionLimiter::new(limits);
+        let unix = ClientRef::Unix(UnixPeer { id: 0, pid: 1, uid: 0 });
+
+        let _first = limiter.admit(unix).unwrap();
+        let _second = limiter.admit(unix).unwrap();
+        assert!(matches!(limiter.admit(unix), Err(Refusal::TooManyClients)));
+    }
+
+    #[test]
+    fn max_instances() {
+        let limiter = ConnectionLimiter::new(Limits { max_instances: Some(1), ..Limits::default() });
+        let first = limiter.admit_instance().unwrap();
+        assert!(matches!(limiter.admit_instance(), Err(Refusal::TooManyInstances)));
+
+        drop(first);
+        assert!(limiter.admit_instance().is_ok());
+    }
+}
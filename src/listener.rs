@@ -0,0 +1,421 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net as std_unix;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::http::{Response, StatusCode};
+
+use crate::client::broadcast::Streams;
+use crate::client::queue::QueueConfig;
+use crate::client::{ClientRef, Connection, Role, Transport, UnixPeer};
+use crate::client::protocol::{self, Mode};
+use crate::http;
+use crate::telnet::TelnetStream;
+
+/// How long to spend telling a refused client why, before just closing the connection.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most refused clients of a listener being told why at once. Further ones are disconnected
+/// right away.
+const MAX_REFUSING: usize = 64;
+
+/// A socket accepting client connections.
+pub struct Listener {
+    socket: Socket,
+    tls: Option<TlsAcceptor>,
+    protocol: Protocol,
+    page: Option<Arc<str>>,
+    origins: Arc<[String]>,
+    role: Role,
+    queue: QueueConfig,
+    streams: Streams,
+    /// The number of refused clients being told why.
+    refusing: Arc<AtomicUsize>,
+}
+
+/// The protocol clients of a listener communicate with.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Protocol {
+    Raw,
+    WebSocket,
+
+    /// The telnet protocol, with the client leaving echoing and line editing to conlink in
+    /// character mode.
+    Telnet { character_mode: bool },
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener, usize),
+}
+
+/// A connection that has been accepted but not yet set up.
+pub struct Accepted {
+    stream: Box<dyn Connection>,
+    peer: ClientRef,
+    tls: Option<TlsAcceptor>,
+    protocol: Protocol,
+    page: Option<Arc<str>>,
+    origins: Arc<[String]>,
+    role: Role,
+    queue: QueueConfig,
+    streams: Streams,
+    refusing: Arc<AtomicUsize>,
+}
+
+impl Listener {
+    pub async fn bind_tcp(addr: SocketAddr) -> io::Result<Listener> {
+        Ok(Listener::new(Socket::Tcp(TcpListener::bind(addr).await?)))
+    }
+
+    pub fn bind_unix(config: &UnixConfig) -> io::Result<Listener> {
+        let listener = match config.path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(name) => {
+                if config.mode.is_some() || config.owner.is_some() || config.group.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "abstract sockets can't have permissions"));
+                }
+
+                let addr = std_unix::SocketAddr::from_abstract_name(name.as_bytes())?;
+                std_unix::UnixListener::bind_addr(&addr)?
+            }
+            None => {
+                config.remove_stale_socket()?;
+
+                // the socket is created in a directory only conlink can access and moved into place
+                // once its permissions are set, so that nobody can connect before that
+                let dir = private_dir(&config.path)?;
+                let path = dir.join("socket");
+                let result = bind_private(config, &path);
+
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_dir(&dir);
+                result?
+            }
+        };
+
+        Ok(Listener::new(Socket::Unix(UnixListener::from_std(listener)?, 0)))
+    }
+
+    fn new(socket: Socket) -> Self {
+        Listener {
+            socket,
+            tls: None,
+            protocol: Protocol::Raw,
+            page: None,
+            origins: Arc::new([]),
+            role: Role::Operator,
+            queue: QueueConfig::default(),
+            streams: Streams::ALL,
+            refusing: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Set the protocol clients of this listener communicate with.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Require connections to this listener to be encrypted using TLS.
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Serve a web page to plain HTTP requests to a WebSocket listener.
+    pub fn with_page(mut self, page: Arc<str>) -> Self {
+        self.page = Some(page);
+        self
+    }
+
+    /// Accept WebSocket connections from pages on other sites with these origins, in addition to
+    /// pages served by conlink itself.
+    pub fn with_origins(mut self, origins: Arc<[String]>) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// Set the role of clients connecting through this listener. Clients can't gain more
+    /// privileges than this by authenticating.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Set how output to clients of this listener is queued.
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Set which of the program's output streams clients of this listener receive.
+    pub fn with_streams(mut self, streams: Streams) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// Wait for a new connection.
+    pub async fn accept(&mut self) -> io::Result<Accepted> {
+        let (stream, peer): (Box<dyn Connection>, _) = match &mut self.socket {
+            Socket::Tcp(l) => {
+                let (stream, addr) = l.accept().await?;
+                (Box::new(stream), ClientRef::Net(addr))
+            }
+            Socket::Unix(l, next_id) => {
+                let (stream, _) = l.accept().await?;
+                let peer = peer_credentials(&stream, *next_id)?;
+                *next_id += 1;
+                (Box::new(stream), ClientRef::Unix(peer))
+            }
+        };
+
+        Ok(Accepted {
+            stream,
+            peer,
+            tls: self.tls.clone(),
+            protocol: self.protocol,
+            page: self.page.clone(),
+            origins: self.origins.clone(),
+            role: self.role,
+            queue: self.queue,
+            streams: self.streams,
+            refusing: self.refusing.clone(),
+        })
+    }
+}
+
+impl Accepted {
+    pub fn peer(&self) -> ClientRef {
+        self.peer
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn queue(&self) -> QueueConfig {
+        self.queue
+    }
+
+    pub fn streams(&self) -> Streams {
+        self.streams
+    }
+
+    /// Perform the handshakes required by the listener, returning the stream to communicate with
+    /// the client through. This is separate from accepting the connection so that a slow client
+    /// doesn't hold up the listener. Returns `None` if the connection was only a request for the
+    /// web page. WebSocket clients can ask for the multiplexed protocol during the handshake,
+    /// the returned mode is the result of that.
+    pub async fn establish(self) -> io::Result<Option<(Transport, Mode)>> {
+        let stream: Box<dyn Connection> = match self.tls {
+            Some(acceptor) => Box::new(acceptor.accept(self.stream).await?),
+            None => self.stream,
+        };
+
+        let mut mode = Mode::Plain;
+
+        let transport = match self.protocol {
+            Protocol::Raw => Transport::Raw(stream),
+            Protocol::Telnet { character_mode } => Transport::Telnet(Box::new(TelnetStream::start(stream, character_mode).await?)),
+            Protocol::WebSocket => {
+                let stream = match &self.page {
+                    Some(page) => match http::serve(stream, page).await? {
+                        Some(stream) => stream,
+                        None => return Ok(None),
+                    },
+                    None => stream,
+                };
+
+                let origins = &self.origins;
+
+                // the error type is given by tungstenite
+                #[allow(clippy::result_large_err)]
+                let callback = |request: &_, response| {
+                    if !http::origin_allowed(request, origins) {
+                        let mut forbidden = Response::new(Some("origin not allowed\n".to_owned()));
+                        *forbidden.status_mut() = StatusCode::FORBIDDEN;
+                        return Err(forbidden);
+                    }
+
+                    let (response, requested) = protocol::negotiate_ws(request, response);
+                    mode = requested;
+                    Ok(response)
+                };
+
+                let ws = tokio_tungstenite::accept_hdr_async(stream, callback).await
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Transport::WebSocket(Box::new(ws))
+            }
+        };
+
+        Ok(Some((transport, mode)))
+    }
+
+    /// Tell the client why its connection isn't accepted and close it, in the background. Only
+    /// clients that don't need a handshake first are told, so that refusing stays cheap for a
+    /// client retrying in a loop; others are disconnected right away.
+    pub fn refuse(self, reason: &str) {
+        if self.tls.is_some() || self.protocol == Protocol::WebSocket {
+            return;
+        }
+
+        if self.refusing.fetch_add(1, Ordering::Relaxed) >= MAX_REFUSING {
+            self.refusing.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+
+        let msg = format!("conlink: connection refused: {}\n", reason);
+        let mut stream = self.stream;
+        let refusing = self.refusing;
+
+        tokio::spawn(async move {
+            // the client may already be gone, and there's nothing to do about it
+            let _ = tokio::time::timeout(REFUSE_TIMEOUT, async {
+                stream.write_all(msg.as_bytes()).await?;
+                stream.shutdown().await
+            }).await;
+
+            refusing.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Removes the file of a Unix domain socket when dropped.
+pub struct SocketFile<'a>(pub &'a UnixConfig);
+
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.remove_socket() {
+            eprintln!("failed to remove {}: {}", self.0.path.display(), e);
+        }
+    }
+}
+
+/// Bind a socket at a temporary path, set its permissions and move it to the configured path.
+fn bind_private(config: &UnixConfig, path: &Path) -> io::Result<std_unix::UnixListener> {
+    let listener = std_unix::UnixListener::bind(path)?;
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    if config.owner.is_some() || config.group.is_some() {
+        std::os::unix::fs::chown(path, config.owner, config.group)?;
+    }
+
+    fs::rename(path, &config.path)?;
+    Ok(listener)
+}
+
+/// Create a directory next to the given path that only the current user can access.
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Settings for a Unix domain socket listener.
+#[derive(Debug, Clone)]
+pub struct UnixConfig {
+    /// The socket path. Paths starting with `@` are bound in the abstract namespace.
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl UnixConfig {
+    /// Remove the socket file, if there is one. Sockets in the abstract namespace disappear by
+    /// themselves.
+    pub fn remove_socket(&self) -> io::Result<()> {
+        if self.path.to_str().is_some_and(|p| p.starts_with('@')) {
+            return Ok(());
+        }
+
+        match fs::symlink_metadata(&self.path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&self.path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove a socket left behind by an instance that is no longer running. A socket that is
+    /// still being listened on, or can't be checked, is left alone.
+    fn remove_stale_socket(&self) -> io::Result<()> {
+        match fs::symlink_metadata(&self.path) {
+            Ok(meta) if meta.file_type().is_socket() => {}
+            _ => return Ok(()),
+        }
+
+        match std_unix::UnixStream::connect(&self.path) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another process is listening on {}", self.path.display()))),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(&self.path),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn peer_credentials(stream: &UnixStream, id: usize) -> io::Result<UnixPeer> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let r = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if r == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(UnixPeer { id, pid: cred.pid as u32, uid: cred.uid })
+}
+
+/// Look up a user by name or numeric ID.
+pub fn resolve_user(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let pw = unsafe { libc::getpwnam(c_name.as_ptr()) };
+
+    if pw.is_null() {
+        Err(format!("unknown user '{}'", name))
+    } else {
+        Ok(unsafe { (*pw).pw_uid })
+    }
+}
+
+/// Look up a group by name or numeric ID.
+pub fn resolve_group(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name '{}'", name))?;
+    let gr = unsafe { libc::getgrnam(c_name.as_ptr()) };
+
+    if gr.is_null() {
+        Err(format!("unknown group '{}'", name))
+    } else {
+        Ok(unsafe { (*gr).gr_gid })
+    }
+}
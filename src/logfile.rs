@@ -0,0 +1,225 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::framing::Framing;
+use crate::rotate::{self, RotateConfig};
+use crate::sink::{self, Background, Sink};
+
+/// Settings for writing the program's output to a file.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub path: PathBuf,
+    /// Whether every line starts with the time it was output at.
+    pub timestamps: bool,
+    /// When to start a new file.
+    pub rotate: RotateConfig,
+    /// Whether finished files are compressed with gzip.
+    pub compress: bool,
+}
+
+enum Record {
+    Output(SystemTime, Vec<u8>),
+    /// Open the file again, after it has been moved away by an external tool.
+    Reopen,
+}
+
+/// Appends the program's output to a file, written in the background by a [`Sink`].
+///
+/// When the file reaches its size or time limit, it is renamed by adding a number to its name,
+/// like `output.1.log`, and a new one is started at the original path. The file is also opened
+/// again on SIGHUP, for use with logrotate.
+#[derive(Clone)]
+pub struct OutputLog {
+    writer: Background<Record>,
+}
+
+impl OutputLog {
+    /// Start logging. The file is opened right away, so that problems with it show up when
+    /// conlink starts.
+    pub fn start(config: LogConfig, framing: Framing) -> io::Result<Self> {
+        let writer = Background::start("output-log", Writer::open(config, framing)?)?;
+        Ok(OutputLog { writer })
+    }
+
+    /// Log output from the program.
+    pub fn output(&self, data: &[u8]) {
+        self.writer.send(Record::Output(SystemTime::now(), data.to_vec()));
+    }
+
+    /// Wait until everything logged so far has been written out.
+    pub async fn flush(&self) {
+        self.writer.flush().await
+    }
+
+    /// Start a task opening the file again whenever SIGHUP is received.
+    pub fn reopen_on_hangup(&self) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let this = self.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                this.writer.send_waiting(Record::Reopen).await;
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Writes output to the current file, starting a new one when it reaches a limit.
+struct Writer {
+    config: LogConfig,
+    framing: Framing,
+    file: BufWriter<File>,
+    started: Instant,
+    written: usize,
+    /// Whether the next output starts a new line.
+    line_start: bool,
+}
+
+impl Writer {
+    fn open(config: LogConfig, framing: Framing) -> io::Result<Self> {
+        let (file, written) = open_append(&config.path)?;
+
+        Ok(Writer {
+            config,
+            framing,
+            file,
+            started: Instant::now(),
+            written,
+            line_start: true,
+        })
+    }
+
+    fn write_lines(&mut self, time: SystemTime, data: &[u8]) -> io::Result<()> {
+        if !self.config.timestamps {
+            self.written += data.len();
+            return self.file.write_all(data);
+        }
+
+        let stamp = timestamp(time);
+
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                self.file.write_all(stamp.as_bytes())?;
+                self.written += stamp.len();
+            }
+
+            self.file.write_all(line)?;
+            self.written += line.len();
+            self.line_start = line.ends_with(b"\n");
+        }
+
+        Ok(())
+    }
+
+    /// Move the current file out of the way and start a new one, compressing the old one in the
+    /// background if configured to.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let finished = rotate::numbered_path(&self.config.path);
+        fs::rename(&self.config.path, &finished)?;
+
+        let (file, written) = open_append(&self.config.path)?;
+        self.file = file;
+        self.written = written;
+        self.started = Instant::now();
+
+        if self.config.compress {
+            thread::spawn(move || {
+                if let Err(e) = compress(&finished) {
+                    eprintln!("failed to compress {}: {}", finished.display(), e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for Writer {
+    type Record = Record;
+
+    fn describe(&self) -> String {
+        format!("output log to {}", self.config.path.display())
+    }
+
+    fn write(&mut self, record: Record) -> io::Result<()> {
+        match record {
+            Record::Output(time, data) => {
+                if self.config.rotate.is_due(self.written, self.started) {
+                    self.rotate()?;
+                }
+
+                let data = sink::encode(self.framing, &data);
+                self.write_lines(time, &data)?;
+            }
+            Record::Reopen => {
+                self.file.flush()?;
+                let (file, written) = open_append(&self.config.path)?;
+                self.file = file;
+                self.written = written;
+                self.started = Instant::now();
+                eprintln!("reopened output log {}", self.config.path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dropped(&mut self, count: usize) -> io::Result<()> {
+        let mut marker = format!("[conlink: {} records dropped]\n", count);
+
+        if !self.line_start {
+            marker.insert(0, '\n');
+        }
+
+        self.write_lines(SystemTime::now(), marker.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Open a file for appending, returning it along with its current size.
+fn open_append(path: &Path) -> io::Result<(BufWriter<File>, usize)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len() as usize;
+    Ok((BufWriter::new(file), len))
+}
+
+/// Replace a file with a gzip-compressed version of it.
+fn compress(path: &Path) -> io::Result<()> {
+    let target = rotate::compressed_path(path);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&target)?), Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    fs::remove_file(path)
+}
+
+/// Format a time as an ISO 8601 timestamp in local time, followed by a space.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    let offset = tm.tm_gmtoff / 60;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}{:02}:{:02} ",
+        tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday,
+        tm.tm_hour, tm.tm_min, tm.tm_sec, since_epoch.subsec_millis(),
+        if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60,
+    )
+}
@@ -1,26 +1,58 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::ops::Deref;
+use std::path::PathBuf;
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{crate_authors, crate_description, crate_name, crate_version};
 use clap::app_from_crate;
 use clap::Arg;
-use tokio::net::TcpListener;
-use tokio::process::{Child, ChildStderr, ChildStdout};
-use tokio::stream::StreamExt;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-use client::bin::Client as BinClient;
-use client::bin::shared::Shared as BinShared;
-use client::str::Client as StrClient;
-use client::str::shared::Shared as StrShared;
 
-use crate::client::{Client, Shared};
-use crate::cmd::{BinReadWrapper, ReadWrapper, StrReadWrapper};
+use crate::access::{AccessControl, AccessRules};
+use crate::auth::{Authenticator, Credential};
+use crate::client::{Client, ClientRef, Identity, Role};
+use crate::client::broadcast::{Audience, Broadcast, Streams};
+use crate::client::event::Event;
+use crate::client::queue::{self, QueueConfig, SlowClientPolicy};
+use crate::client::scrollback::ScrollbackLimit;
+use crate::client::shared::Shared;
+use crate::client::window::ResizePolicy;
+use crate::cmd::StderrLabel;
+use crate::framing::Framing;
+use crate::instance::Instances;
+use crate::limits::{ConnectionLimiter, Limits};
+use crate::listener::{Listener, Protocol, SocketFile, UnixConfig};
+use crate::logfile::{LogConfig, OutputLog};
+use crate::pty::{PtyConfig, WindowSize};
+use crate::record::{RecordConfig, Recorder};
+use crate::rotate::RotateConfig;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{RestartConfig, Supervisor};
+use crate::tls::TlsConfig;
 
 mod client;
 mod cmd;
+mod access;
 mod asyncreadwrap;
+mod auth;
+mod framing;
+mod http;
+mod instance;
+mod limits;
+mod logfile;
+mod listener;
+mod pty;
+mod record;
+mod rotate;
+mod shutdown;
+mod sink;
+mod supervisor;
+mod telnet;
+mod tls;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -30,71 +62,571 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = app_from_crate!()
         .arg(Arg::with_name("port").short("p").long("port").default_value("1337").help("The port to bind the socket to"))
         .arg(Arg::with_name("host").short("H").long("host").default_value("0.0.0.0").help("The host to bind the socket to"))
+        .arg(Arg::with_name("observer-port").short("o").long("observer-port").takes_value(true).help("Also accept read-only connections on this port"))
+        .arg(Arg::with_name("stderr-port").long("stderr-port").takes_value(true).help("Send the program's error output only to read-only clients connecting on this port, instead of mixing it into the output"))
+        .arg(Arg::with_name("ws-port").short("w").long("ws-port").takes_value(true).help("Also accept WebSocket connections on this port, and serve a web terminal at /"))
+        .arg(Arg::with_name("ws-origin").long("ws-origin").takes_value(true).multiple(true).number_of_values(1).requires("ws-port").help("Also accept WebSocket connections from web pages with this origin, like https://example.com, can be given multiple times"))
+        .arg(Arg::with_name("telnet-port").long("telnet-port").takes_value(true).help("Also accept telnet connections on this port"))
+        .arg(Arg::with_name("tls-cert").long("tls-cert").takes_value(true).requires("tls-key").help("Accept only TLS connections on the TCP sockets, using the certificate chain in this PEM file"))
+        .arg(Arg::with_name("tls-key").long("tls-key").takes_value(true).requires("tls-cert").help("The PEM file containing the private key for the TLS certificate"))
+        .arg(Arg::with_name("tls-client-ca").long("tls-client-ca").takes_value(true).requires("tls-cert").help("Require TLS clients to present a certificate signed by a CA in this PEM file"))
+        .arg(Arg::with_name("allow").long("allow").takes_value(true).multiple(true).number_of_values(1).help("Only accept connections from this network (CIDR notation), can be given multiple times"))
+        .arg(Arg::with_name("deny").long("deny").takes_value(true).multiple(true).number_of_values(1).help("Reject connections from this network (CIDR notation), can be given multiple times"))
+        .arg(Arg::with_name("access-file").long("access-file").takes_value(true).help("Read additional allow/deny rules from this file, reloaded on SIGHUP"))
+        .arg(Arg::with_name("max-clients").long("max-clients").takes_value(true).help("The maximum number of network clients connected at once"))
+        .arg(Arg::with_name("max-per-ip").long("max-per-ip").takes_value(true).help("The maximum number of connections from a single address at once"))
+        .arg(Arg::with_name("rate-limit").long("rate-limit").takes_value(true).help("The maximum number of connection attempts from a single address in a period, as <count>/<seconds>"))
+        .arg(Arg::with_name("slow-clients").long("slow-clients").takes_value(true).multiple(true).number_of_values(1).help("What to do when a client can't keep up with output: block, drop or disconnect; prefix with tcp=, observer=, ws=, stderr=, telnet= or unix= to set it for one listener"))
+        .arg(Arg::with_name("max-queue").long("max-queue").takes_value(true).default_value("4096").help("The maximum number of messages waiting to be sent to a client"))
+        .arg(Arg::with_name("max-queue-bytes").long("max-queue-bytes").takes_value(true).default_value("4M").help("The maximum amount of output waiting to be sent to a client, in bytes or with a K/M suffix"))
+        .arg(Arg::with_name("password-file").long("password-file").takes_value(true).help("Require clients to enter the password contained in this file"))
+        .arg(Arg::with_name("token-file").long("token-file").takes_value(true).help("Require clients to enter one of the tokens in this file, given as <name>:<token> per line"))
+        .arg(Arg::with_name("observer-token-file").long("observer-token-file").takes_value(true).help("Like --token-file, but clients using these tokens can't send input"))
+        .arg(Arg::with_name("unix").short("U").long("unix").takes_value(true).help("Listen on a Unix domain socket at this path instead of TCP (prefix with @ for the abstract namespace)"))
+        .arg(Arg::with_name("unix-mode").long("unix-mode").takes_value(true).requires("unix").help("The permissions (in octal) to set on the Unix domain socket"))
+        .arg(Arg::with_name("unix-owner").long("unix-owner").takes_value(true).requires("unix").help("The user to set as owner of the Unix domain socket"))
+        .arg(Arg::with_name("unix-group").long("unix-group").takes_value(true).requires("unix").help("The group to set as owner of the Unix domain socket"))
         .arg(Arg::with_name("quiet").short("q").long("quiet").help("Disable passthrough of command output/input to stdout/stdin"))
-        .arg(Arg::with_name("binary").short("b").long("binary").help("Enable binary mode"))
+        .arg(Arg::with_name("binary").short("b").long("binary").conflicts_with("framing").help("Enable binary mode, the same as --framing raw"))
+        .arg(Arg::with_name("framing").short("f").long("framing").takes_value(true).help("How input and output are split into messages: lines, raw, nul, length (32 bit big-endian prefix), delim:<byte> or fixed:<size> [default: lines, or raw with --pty]"))
         .arg(Arg::with_name("echo").short("e").long("echo").help("Send input from client to other clients"))
+        .arg(Arg::with_name("exclusive-input").short("x").long("exclusive-input").help("Only pass input from the client holding the input lock, taken by sending ~lock and released with ~unlock"))
+        .arg(Arg::with_name("multiplexed").long("multiplexed").help("Let clients on TCP and Unix socket connections ask for the multiplexed protocol, delaying plain clients briefly"))
+        .arg(Arg::with_name("allow-signals").long("allow-signals").help("Let clients send signals to the command with ~signal <name>, or to its process group with ~signal-group <name>"))
+        .arg(Arg::with_name("scrollback").short("s").long("scrollback").takes_value(true).default_value("0").help("Amount of output to replay to new clients, in lines or with a B/K/M suffix in bytes"))
+        .arg(Arg::with_name("stderr-prefix").long("stderr-prefix").takes_value(true).help("Put this text in front of every line of error output"))
+        .arg(Arg::with_name("stderr-color").long("stderr-color").help("Color error output red"))
+        .arg(Arg::with_name("stderr-local").long("stderr-local").conflicts_with("stderr-port").help("Only show error output on the terminal, not to network clients"))
+        .arg(Arg::with_name("restart").long("restart").takes_value(true).possible_values(&["never", "always", "on-failure"]).default_value("never").help("When to restart the command after it exits, with increasing delays between restarts"))
+        .arg(Arg::with_name("restart-limit").long("restart-limit").takes_value(true).help("The maximum number of times to restart the command"))
+        .arg(Arg::with_name("on-demand").long("on-demand").help("Only run the command while network clients are connected, starting it when the first one connects"))
+        .arg(Arg::with_name("grace-period").long("grace-period").takes_value(true).requires("on-demand").help("How many seconds to keep the command running after the last client disconnects [default: 10]"))
+        .arg(Arg::with_name("per-connection").long("per-connection").conflicts_with_all(&["on-demand", "echo", "exclusive-input", "scrollback", "resize-policy"]).help("Run a separate instance of the command for every client, connected only to it (conlink then exits with 0, not a command's status)"))
+        .arg(Arg::with_name("max-instances").long("max-instances").takes_value(true).requires("per-connection").help("The maximum number of instances of the command running at once"))
+        .arg(Arg::with_name("pty").short("t").long("pty").help("Run the command on a pseudo-terminal, passing input and output on unchanged unless --framing is given"))
+        .arg(Arg::with_name("term").long("term").takes_value(true).default_value("xterm-256color").help("The value of TERM for the command in pseudo-terminal mode"))
+        .arg(Arg::with_name("size").long("size").takes_value(true).default_value("80x24").help("The initial window size (<cols>x<rows>) in pseudo-terminal mode"))
+        .arg(Arg::with_name("resize-policy").long("resize-policy").takes_value(true).possible_values(&["smallest", "latest", "lock-holder"]).default_value("latest").help("Whose window size the pseudo-terminal gets with several clients: the smallest, the most recently changed or the input lock holder's"))
+        .arg(Arg::with_name("record").long("record").takes_value(true).conflicts_with("per-connection").help("Record the session to this file in asciicast v2 format"))
+        .arg(Arg::with_name("record-input").long("record-input").requires("record").help("Also record input sent by clients"))
+        .arg(Arg::with_name("record-max-size").long("record-max-size").takes_value(true).requires("record").help("Start a new recording once the current one reaches this size, in bytes or with a K/M suffix"))
+        .arg(Arg::with_name("record-max-time").long("record-max-time").takes_value(true).requires("record").help("Start a new recording once the current one has gone on for this many seconds"))
+        .arg(Arg::with_name("log").long("log").takes_value(true).conflicts_with("per-connection").help("Append the command's output to this file, reopened on SIGHUP"))
+        .arg(Arg::with_name("log-timestamps").long("log-timestamps").requires("log").help("Start every line in the output log with the time it was output at"))
+        .arg(Arg::with_name("log-max-size").long("log-max-size").takes_value(true).requires("log").help("Start a new output log once the current one reaches this size, in bytes or with a K/M suffix"))
+        .arg(Arg::with_name("log-max-time").long("log-max-time").takes_value(true).requires("log").help("Start a new output log once the current one has been written to for this many seconds"))
+        .arg(Arg::with_name("log-compress").long("log-compress").requires("log").help("Compress finished output logs with gzip"))
         .arg(Arg::with_name("command").last(true).required(true).multiple(true).help("The command to run"))
         .get_matches();
 
-    let port: u16 = matches.value_of("port").unwrap().parse().expect("invalid port");
-    let host: IpAddr = matches.value_of("host").unwrap().parse().expect("invalid target IP address");
-    let quiet = matches.is_present("quiet");
-    let binary = matches.is_present("binary");
-    let echo = matches.is_present("echo");
+    let unix = match matches.value_of_os("unix") {
+        Some(path) => Some(UnixConfig {
+            path: path.into(),
+            mode: match matches.value_of("unix-mode") {
+                Some(mode) => Some(u32::from_str_radix(mode, 8).map_err(|_| format!("invalid socket mode '{}'", mode))?),
+                None => None,
+            },
+            owner: matches.value_of("unix-owner").map(listener::resolve_user).transpose()?,
+            group: matches.value_of("unix-group").map(listener::resolve_group).transpose()?,
+        }),
+        None => None,
+    };
+
+    let mut options = Options {
+        port: matches.value_of("port").unwrap().parse().expect("invalid port"),
+        host: matches.value_of("host").unwrap().parse().expect("invalid target IP address"),
+        observer_port: matches.value_of("observer-port").map(|p| p.parse().expect("invalid observer port")),
+        ws_port: matches.value_of("ws-port").map(|p| p.parse().expect("invalid WebSocket port")),
+        ws_origins: matches.values_of("ws-origin").into_iter().flatten().map(str::to_owned).collect(),
+        stderr_port: matches.value_of("stderr-port").map(|p| p.parse().expect("invalid stderr port")),
+        telnet_port: matches.value_of("telnet-port").map(|p| p.parse().expect("invalid telnet port")),
+        // when listening on a Unix domain socket, only listen on TCP if explicitly requested
+        tcp: unix.is_none() || matches.occurrences_of("port") > 0 || matches.occurrences_of("host") > 0,
+        unix,
+        access: AccessRules::new(
+            matches.values_of("allow").into_iter().flatten().map(access::parse_net).collect::<Result<_, _>>()?,
+            matches.values_of("deny").into_iter().flatten().map(access::parse_net).collect::<Result<_, _>>()?,
+        ),
+        access_file: matches.value_of_os("access-file").map(Into::into),
+        limits: Limits {
+            max_clients: matches.value_of("max-clients").map(str::parse).transpose()?,
+            max_per_ip: matches.value_of("max-per-ip").map(str::parse).transpose()?,
+            rate: matches.value_of("rate-limit").map(str::parse).transpose()?,
+            max_instances: matches.value_of("max-instances").map(str::parse).transpose()?,
+        },
+        queue: QueueConfig {
+            policy: SlowClientPolicy::Block,
+            max_messages: matches.value_of("max-queue").unwrap().parse()?,
+            max_bytes: queue::parse_size(matches.value_of("max-queue-bytes").unwrap())?,
+        },
+        slow_clients: HashMap::new(),
+        password_file: matches.value_of_os("password-file").map(Into::into),
+        token_file: matches.value_of_os("token-file").map(Into::into),
+        observer_token_file: matches.value_of_os("observer-token-file").map(Into::into),
+        tls: match (matches.value_of_os("tls-cert"), matches.value_of_os("tls-key")) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: matches.value_of_os("tls-client-ca").map(Into::into),
+            }),
+            _ => None,
+        },
+        quiet: matches.is_present("quiet"),
+        // a terminal's output, like prompts, isn't made of lines
+        framing: match matches.value_of("framing") {
+            _ if matches.is_present("binary") => Framing::Raw,
+            Some(framing) => framing.parse()?,
+            None if matches.is_present("pty") => Framing::Raw,
+            None => Framing::Lines,
+        },
+        echo: matches.is_present("echo"),
+        exclusive_input: matches.is_present("exclusive-input"),
+        signals: matches.is_present("allow-signals"),
+        multiplexed: matches.is_present("multiplexed"),
+        stderr_label: StderrLabel {
+            prefix: matches.value_of("stderr-prefix").map(str::to_owned),
+            color: matches.is_present("stderr-color"),
+        },
+        stderr_local: matches.is_present("stderr-local"),
+        scrollback: matches.value_of("scrollback").unwrap().parse()?,
+        restart: RestartConfig {
+            policy: matches.value_of("restart").unwrap().parse()?,
+            limit: matches.value_of("restart-limit").map(str::parse).transpose()?,
+        },
+        per_connection: matches.is_present("per-connection"),
+        on_demand: if matches.is_present("on-demand") {
+            Some(Duration::from_secs(matches.value_of("grace-period").unwrap_or("10").parse()?))
+        } else {
+            None
+        },
+        resize: matches.value_of("resize-policy").unwrap().parse()?,
+        record: match matches.value_of_os("record") {
+            Some(path) => Some(RecordConfig {
+                path: path.into(),
+                input: matches.is_present("record-input"),
+                rotate: RotateConfig {
+                    max_size: matches.value_of("record-max-size").map(queue::parse_size).transpose()?,
+                    max_time: matches.value_of("record-max-time").map(|s| s.parse().map(Duration::from_secs)).transpose()?,
+                },
+            }),
+            None => None,
+        },
+        log: match matches.value_of_os("log") {
+            Some(path) => Some(LogConfig {
+                path: path.into(),
+                timestamps: matches.is_present("log-timestamps"),
+                rotate: RotateConfig {
+                    max_size: matches.value_of("log-max-size").map(queue::parse_size).transpose()?,
+                    max_time: matches.value_of("log-max-time").map(|s| s.parse().map(Duration::from_secs)).transpose()?,
+                },
+                compress: matches.is_present("log-compress"),
+            }),
+            None => None,
+        },
+        pty: if matches.is_present("pty") {
+            Some(PtyConfig {
+                term: matches.value_of("term").unwrap().to_owned(),
+                size: matches.value_of("size").unwrap().parse::<WindowSize>()?,
+            })
+        } else {
+            None
+        },
+    };
+    for policy in matches.values_of("slow-clients").into_iter().flatten() {
+        match policy.find('=') {
+            Some(pos) => {
+                let listener = &policy[..pos];
+
+                if !LISTENER_NAMES.contains(&listener) {
+                    Err(format!("unknown listener '{}', expected one of {}", listener, LISTENER_NAMES.join(", ")))?;
+                }
+
+                options.slow_clients.insert(listener.to_owned(), policy[pos + 1..].parse()?);
+            }
+            None => options.queue.policy = policy.parse()?,
+        }
+    }
+
+    if options.resize == ResizePolicy::LockHolder && !options.exclusive_input {
+        Err("--resize-policy lock-holder needs --exclusive-input")?;
+    }
+
     let command = matches.values_of_lossy("command").unwrap();
 
-    // TODO: find a way to cleanly exit?
-    std::process::exit(start(&command, host, port, quiet, binary, echo).await?)
+    std::process::exit(start(&command, &options).await?)
+}
+
+/// How long to wait for clients to receive the rest of the output when exiting, before closing
+/// their connections anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The window size recorded for a command that isn't running on a pseudo-terminal.
+const DEFAULT_SIZE: WindowSize = WindowSize { cols: 80, rows: 24 };
+
+/// The names of listeners that settings can be given for separately.
+const LISTENER_NAMES: &[&str] = &["tcp", "observer", "ws", "stderr", "telnet", "unix"];
+
+/// The settings given on the command line.
+struct Options {
+    port: u16,
+    host: IpAddr,
+    observer_port: Option<u16>,
+    ws_port: Option<u16>,
+    ws_origins: Vec<String>,
+    stderr_port: Option<u16>,
+    telnet_port: Option<u16>,
+    tcp: bool,
+    unix: Option<UnixConfig>,
+    tls: Option<TlsConfig>,
+    access: AccessRules,
+    access_file: Option<PathBuf>,
+    limits: Limits,
+    /// How output to clients is queued, unless overridden for a listener.
+    queue: QueueConfig,
+    /// Slow client policies for specific listeners, by name.
+    slow_clients: HashMap<String, SlowClientPolicy>,
+    password_file: Option<PathBuf>,
+    token_file: Option<PathBuf>,
+    observer_token_file: Option<PathBuf>,
+    quiet: bool,
+    framing: Framing,
+    echo: bool,
+    exclusive_input: bool,
+    /// Whether clients may send signals to the command.
+    signals: bool,
+    /// Whether clients on raw connections may ask for the multiplexed protocol.
+    multiplexed: bool,
+    stderr_label: StderrLabel,
+    /// Whether error output is kept from network clients.
+    stderr_local: bool,
+    scrollback: ScrollbackLimit,
+    restart: RestartConfig,
+    /// If set, the command only runs while clients are connected, with this grace period after
+    /// the last one leaves.
+    on_demand: Option<Duration>,
+    /// Whether every client gets its own instance of the command.
+    per_connection: bool,
+    /// Which client's window size the pseudo-terminal gets.
+    resize: ResizePolicy,
+    record: Option<RecordConfig>,
+    log: Option<LogConfig>,
+    pty: Option<PtyConfig>,
 }
 
-async fn start(command: &[String], host: IpAddr, port: u16, quiet: bool, binary: bool, echo: bool) -> Result<i32, Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind((host, port)).await?;
+impl Options {
+    /// The output streams sent to clients of the regular listeners.
+    fn streams(&self) -> Streams {
+        Streams {
+            stdout: true,
+            stderr: !self.stderr_local && self.stderr_port.is_none(),
+        }
+    }
+
+    /// How output to clients of the named listener is queued.
+    fn queue_for(&self, listener: &str) -> QueueConfig {
+        let mut queue = self.queue;
+
+        if let Some(&policy) = self.slow_clients.get(listener) {
+            queue.policy = policy;
+        }
+
+        queue
+    }
+}
+
+async fn start(command: &[String], options: &Options) -> Result<i32, Box<dyn std::error::Error>> {
+    let shutdown = Shutdown::on_signals()?;
+    let mut listeners = Vec::new();
+    let tls = match &options.tls {
+        Some(tls) => Some(tls.create_acceptor()?),
+        None => None,
+    };
+
+    if options.tcp {
+        let mut listener = Listener::bind_tcp((options.host, options.port).into()).await?
+            .with_queue(options.queue_for("tcp"))
+            .with_streams(options.streams());
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        listeners.push(listener);
+    }
+
+    if let Some(port) = options.observer_port {
+        let mut listener = Listener::bind_tcp((options.host, port).into()).await?
+            .with_role(Role::Observer)
+            .with_queue(options.queue_for("observer"))
+            .with_streams(options.streams());
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        listeners.push(listener);
+    }
+
+    if let Some(port) = options.ws_port {
+        let mut listener = Listener::bind_tcp((options.host, port).into()).await?
+            .with_protocol(Protocol::WebSocket)
+            .with_page(http::terminal_page(options.framing.is_stream()).into())
+            .with_origins(options.ws_origins.clone().into())
+            .with_queue(options.queue_for("ws"))
+            .with_streams(options.streams());
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        listeners.push(listener);
+    }
+
+    if let Some(port) = options.stderr_port {
+        let mut listener = Listener::bind_tcp((options.host, port).into()).await?
+            .with_role(Role::Observer)
+            .with_queue(options.queue_for("stderr"))
+            .with_streams(Streams { stdout: false, stderr: true });
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        listeners.push(listener);
+    }
+
+    if let Some(port) = options.telnet_port {
+        // a program on a terminal echoes input itself, and handles it one key at a time
+        let protocol = Protocol::Telnet { character_mode: options.pty.is_some() };
+        let mut listener = Listener::bind_tcp((options.host, port).into()).await?
+            .with_protocol(protocol)
+            .with_queue(options.queue_for("telnet"))
+            .with_streams(options.streams());
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        listeners.push(listener);
+    }
+
+    // the socket file is removed again however conlink exits
+    let _socket = match &options.unix {
+        Some(unix) => {
+            listeners.push(Listener::bind_unix(unix)?.with_queue(options.queue_for("unix")).with_streams(options.streams()));
+            Some(SocketFile(unix))
+        }
+        None => None,
+    };
+
+    let auth = if options.password_file.is_some() || options.token_file.is_some() || options.observer_token_file.is_some() {
+        let mut auth = Authenticator::new();
 
-    let mut child = cmd::start_command(&command)?;
+        if let Some(path) = &options.password_file {
+            auth.load_password(path)?;
+        }
 
-    if binary {
-        actually_start::<BinShared, BinClient, BinReadWrapper, Vec<u8>>(listener, &mut child, quiet, echo, BinReadWrapper).await
+        if let Some(path) = &options.token_file {
+            auth.load_tokens(path, Role::Operator)?;
+        }
+
+        if let Some(path) = &options.observer_token_file {
+            auth.load_tokens(path, Role::Observer)?;
+        }
+
+        Some(Arc::new(auth))
     } else {
-        actually_start::<StrShared, StrClient, StrReadWrapper, String>(listener, &mut child, quiet, echo, StrReadWrapper).await
+        None
+    };
+
+    let access = AccessControl::new(options.access.clone(), options.access_file.clone())?;
+    access.reload_on_hangup()?;
+
+    let limiter = ConnectionLimiter::new(options.limits);
+
+    let recorder = match &options.record {
+        Some(config) => {
+            let size = options.pty.as_ref().map_or(DEFAULT_SIZE, |pty| pty.size);
+            let term = options.pty.as_ref().map(|pty| &pty.term[..]);
+            Some(Recorder::start(config.clone(), options.framing, size, command, term)?)
+        }
+        None => None,
+    };
+
+    let log = match &options.log {
+        Some(config) => {
+            let log = OutputLog::start(config.clone(), options.framing)?;
+            log.reopen_on_hangup()?;
+            Some(log)
+        }
+        None => None,
+    };
+
+    let output = Broadcast::new(options.scrollback, |messages, bytes| Event::Skipped { messages, bytes });
+    let state = Shared::new(options.framing, output, options.echo, options.exclusive_input, options.signals, options.resize)
+        .with_recorder(recorder.clone())
+        .with_log(log.clone());
+    let state = Arc::new(Mutex::new(state));
+
+    if options.per_connection {
+        let instances = Instances::new(command, options.pty.clone(), options.stderr_label.clone(), options.restart, options.framing, options.signals, shutdown.clone());
+        actually_start(listeners, access, limiter, auth, state, Some(instances.clone()), shutdown.clone(), options);
+
+        // instances are started as clients connect, and stop their programs themselves
+        shutdown.clone().wait().await;
+        let _ = tokio::time::timeout(supervisor::STOP_TIMEOUT + CLOSE_TIMEOUT, instances.wait_stopped()).await;
+
+        return Ok(0);
+    }
+
+    let supervisor = Supervisor::new(command, options.pty.clone(), options.stderr_label.clone(), options.restart, options.on_demand, state.clone(), shutdown.clone());
+
+    let terminal = actually_start(listeners, access, limiter, auth, state.clone(), None, shutdown, options);
+
+    let status = supervisor.run().await?;
+
+    close_clients(&state, status, terminal).await;
+
+    if let Some(recorder) = recorder {
+        recorder.flush().await;
     }
 
-    Ok(child.await?.code().unwrap_or(126))
+    if let Some(log) = log {
+        log.flush().await;
+    }
+
+    Ok(status.map_or(0, supervisor::exit_code))
 }
 
-async fn actually_start<S, C, W, D>(mut listener: TcpListener, child: &mut Child, quiet: bool, echo: bool, wrapper: W)
-    where S: Shared<Data=<D as Deref>::Target> + Send + 'static,
-          C: Client<S> + Send,
-          W: ReadWrapper<ChildStdout, Data=D> + ReadWrapper<ChildStderr, Data=D> + Copy + 'static,
-          D: Deref + Send + Sync,
-          <D as Deref>::Target: Sync {
-    let stdin = child.stdin.take().unwrap();
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
+/// Tell clients that the program has exited, and wait for them to receive the rest of the output
+/// before their connections are closed.
+async fn close_clients(state: &Mutex<Shared>, status: Option<ExitStatus>, terminal: Option<JoinHandle<()>>) {
+    let (output, mut presence) = {
+        let state = state.lock().await;
+        (state.output().clone(), state.presence())
+    };
 
-    let state = Arc::new(Mutex::new(S::new(stdin, echo)));
+    let closed = async {
+        if let Some(status) = status {
+            output.send(Event::Notice(format!("process exited with {}", supervisor::describe(status))), Audience::All).await;
+        }
 
-    cmd::process_stdout(stdout, state.clone(), wrapper);
-    cmd::process_stdout(stderr, state.clone(), wrapper);
+        // clients disconnect once they have received everything
+        output.close();
 
-    if !quiet {
-        let state = state.clone();
-        tokio::spawn(async move {
-            let client = C::new_term(state.clone()).await;
-            if let Err(e) = client.process().await {
-                eprintln!("error while processing terminal client: {:?}", e);
+        while *presence.borrow() > 0 {
+            if presence.recv().await.is_none() {
+                break;
             }
-        });
+        }
+
+        if let Some(terminal) = terminal {
+            let _ = terminal.await;
+        }
+    };
+
+    if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+        eprintln!("closing connections to clients that haven't received all output");
     }
+}
 
-    {
+#[allow(clippy::too_many_arguments)]
+fn actually_start(listeners: Vec<Listener>, access: Arc<AccessControl>, limiter: Arc<ConnectionLimiter>, auth: Option<Arc<Authenticator>>, state: Arc<Mutex<Shared>>, instances: Option<Arc<Instances>>, shutdown: Shutdown, options: &Options) -> Option<JoinHandle<()>> {
+    for mut listener in listeners {
         let state = state.clone();
+        let access = access.clone();
+        let limiter = limiter.clone();
+        let auth = auth.clone();
+        let instances = instances.clone();
+        let mut shutdown = shutdown.clone();
+        let multiplexed = options.multiplexed;
         tokio::spawn(async move {
-            while let Some(stream) = listener.next().await {
-                match stream {
-                    Ok(stream) => {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    // stop accepting connections once conlink is exiting
+                    _ = shutdown.wait() => break,
+                };
+
+                match accepted {
+                    Ok(accepted) => {
+                        if let ClientRef::Net(addr) = accepted.peer() {
+                            if !access.permits(addr.ip()) {
+                                eprintln!("rejected connection from {}", addr);
+                                continue;
+                            }
+                        }
+
+                        let admitted = limiter.admit(accepted.peer()).and_then(|permit| {
+                            let instance = match instances {
+                                Some(_) => Some(limiter.admit_instance()?),
+                                None => None,
+                            };
+
+                            Ok((permit, instance))
+                        });
+
+                        let (permit, instance) = match admitted {
+                            Ok(v) => v,
+                            Err(refusal) => {
+                                eprintln!("refused connection from {}: {}", accepted.peer(), refusal);
+                                accepted.refuse(&refusal.to_string());
+                                continue;
+                            }
+                        };
+
                         let state = state.clone();
+                        let auth = auth.clone();
+                        let instances = instances.clone();
                         tokio::spawn(async move {
-                            let client = C::new_net(stream, state).await;
+                            let _permit = permit;
+                            let _instance = instance;
+                            let peer = accepted.peer();
+                            let role = accepted.role();
+                            let queue = accepted.queue();
+                            let streams = accepted.streams();
+                            let (mut stream, mode) = match accepted.establish().await {
+                                Ok(Some(v)) => v,
+                                Ok(None) => return,
+                                Err(e) => {
+                                    eprintln!("failed to set up connection from {}: {:?}", peer, e);
+                                    return;
+                                }
+                            };
+
+                            let mut identity = Identity::anonymous(peer, role);
+
+                            if let Some(auth) = auth {
+                                match auth.authenticate(&mut stream, peer).await {
+                                    Ok(Some(credential)) => {
+                                        identity.role = identity.role.min(credential.role());
+
+                                        if let Credential::Token(name, _) = credential {
+                                            identity.user = Some(name);
+                                        }
+                                    }
+                                    Ok(None) => return,
+                                    Err(e) => {
+                                        eprintln!("failed to authenticate {}: {:?}", peer, e);
+                                        return;
+                                    }
+                                }
+                            }
+
+                            let (stream, mode) = match stream.negotiate(mode, multiplexed).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    eprintln!("failed to set up connection from {}: {:?}", peer, e);
+                                    return;
+                                }
+                            };
+
+                            if let Some(instances) = instances {
+                                instances.serve(stream, mode, identity, queue, streams).await;
+                                return;
+                            }
+
+                            let client = Client::new_net(stream, mode, identity, queue, streams, state).await;
                             if let Err(e) = client.process().await {
                                 eprintln!("error while processing network client: {:?}", e);
                             }
@@ -105,4 +637,16 @@ async fn actually_start<S, C, W, D>(mut listener: TcpListener, child: &mut Child
             }
         });
     }
+
+    // the terminal can't be connected to every instance of the program
+    if options.quiet || instances.is_some() {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        let client = Client::new_term(state).await;
+        if let Err(e) = client.process().await {
+            eprintln!("error while processing terminal client: {:?}", e);
+        }
+    }))
 }
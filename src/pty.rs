@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::Stdio;
+use std::ptr;
+use std::str::FromStr;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tokio::io::PollEvented;
+
+/// Settings for running the command on a pseudo-terminal.
+#[derive(Debug, Clone)]
+pub struct PtyConfig {
+    /// The value of the TERM environment variable passed to the command.
+    pub term: String,
+
+    /// The initial size of the terminal.
+    pub size: WindowSize,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl WindowSize {
+    /// Get the size of the terminal a file descriptor refers to.
+    pub fn of_terminal(fd: RawFd) -> io::Result<WindowSize> {
+        let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(WindowSize { cols: winsize.ws_col, rows: winsize.ws_row })
+    }
+
+    fn to_winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+impl FromStr for WindowSize {
+    type Err = String;
+
+    /// Parse a window size in the form `<cols>x<rows>`, e.g. `80x24`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.splitn(2, 'x');
+        let cols = iter.next().and_then(|v| v.parse().ok());
+        let rows = iter.next().and_then(|v| v.parse().ok());
+
+        match (cols, rows) {
+            (Some(cols), Some(rows)) => Ok(WindowSize { cols, rows }),
+            _ => Err(format!("invalid window size '{}', expected <cols>x<rows>", s)),
+        }
+    }
+}
+
+/// A freshly opened pseudo-terminal pair.
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    pub fn open(size: WindowSize) -> io::Result<Pty> {
+        let mut master: RawFd = -1;
+        let mut slave: RawFd = -1;
+        let winsize = size.to_winsize();
+
+        let r = unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &winsize) };
+        if r == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+        set_cloexec(master.as_raw_fd())?;
+        set_cloexec(slave.as_raw_fd())?;
+
+        Ok(Pty { master, slave })
+    }
+
+    /// Create a handle to the slave side to be used as one of the command's standard streams.
+    pub fn slave_stdio(&self) -> io::Result<Stdio> {
+        Ok(Stdio::from(self.slave.try_clone()?))
+    }
+
+    /// Create a handle for changing the size of the terminal.
+    pub fn terminal(&self) -> io::Result<Terminal> {
+        Ok(Terminal(self.master.try_clone()?))
+    }
+
+    /// Close the slave side in this process and return the master side, registered with the
+    /// event loop. This must be done after the command is spawned, so that reading from the
+    /// master ends once the command has closed the terminal.
+    pub fn into_master(self) -> io::Result<PollEvented<PtyMaster>> {
+        set_nonblocking(self.master.as_raw_fd())?;
+        PollEvented::new(PtyMaster(self.master))
+    }
+}
+
+/// Make the calling process a session leader with the terminal on its standard input as its
+/// controlling terminal. Only to be called in a forked child before exec.
+pub fn make_controlling_terminal() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// A handle to the pseudo-terminal the program is running on.
+pub struct Terminal(File);
+
+impl Terminal {
+    /// Change the size of the terminal, which sends SIGWINCH to the program.
+    pub fn resize(&self, size: WindowSize) -> io::Result<()> {
+        let winsize = size.to_winsize();
+
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// The master side of a pseudo-terminal.
+pub struct PtyMaster(File);
+
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // reading from the master returns EIO after the last handle to the slave has been
+            // closed, which means the same as EOF for us
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            r => r,
+        }
+    }
+}
+
+impl Write for PtyMaster {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Evented for PtyMaster {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::ClientRef;
+use crate::framing::Framing;
+use crate::pty::WindowSize;
+use crate::rotate::{self, RotateConfig};
+use crate::sink::{self, Background, Sink};
+
+/// Settings for recording the session.
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    pub path: PathBuf,
+    /// Whether input from clients is recorded too.
+    pub input: bool,
+    /// When to start a new recording.
+    pub rotate: RotateConfig,
+}
+
+/// Something that happened in the session.
+enum Record {
+    Output(Vec<u8>),
+    Input(ClientRef, Vec<u8>),
+    Resize(WindowSize),
+}
+
+/// Records the session in asciicast v2 format, which can be replayed with asciinema. Recordings
+/// are written in the background by a [`Sink`].
+///
+/// When a recording reaches its size or time limit, it is renamed by adding a number to its
+/// name, like `session.1.cast`, and a new one is started at the original path. A recording left
+/// at the path by an earlier session is renamed the same way.
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Background<(Instant, Record)>,
+    input: bool,
+}
+
+impl Recorder {
+    /// Start recording, with the header describing the command and the initial size of its
+    /// terminal. The first recording is created right away, so that problems with it show up
+    /// when conlink starts.
+    pub fn start(config: RecordConfig, framing: Framing, size: WindowSize, command: &[String], term: Option<&str>) -> io::Result<Self> {
+        let header = Header {
+            command: command.join(" "),
+            term: term.map(str::to_owned),
+        };
+
+        let input = config.input;
+        let writer = Background::start("recorder", Writer::create(config, framing, header, size)?)?;
+        Ok(Recorder { writer, input })
+    }
+
+    /// Record output from the program.
+    pub fn output(&self, data: &[u8]) {
+        self.send(Record::Output(data.to_vec()));
+    }
+
+    /// Record input a client sent to the program, if input is being recorded.
+    pub fn input(&self, from: ClientRef, data: &[u8]) {
+        if self.input {
+            self.send(Record::Input(from, data.to_vec()));
+        }
+    }
+
+    /// Record the program's terminal changing its size.
+    pub fn resize(&self, size: WindowSize) {
+        self.send(Record::Resize(size));
+    }
+
+    /// Wait until everything recorded so far has been written out.
+    pub async fn flush(&self) {
+        self.writer.flush().await
+    }
+
+    fn send(&self, record: Record) {
+        self.writer.send((Instant::now(), record));
+    }
+}
+
+/// What goes into the header of every recording.
+struct Header {
+    command: String,
+    term: Option<String>,
+}
+
+/// Writes records to the current recording, starting a new one when it reaches a limit.
+struct Writer {
+    config: RecordConfig,
+    framing: Framing,
+    header: Header,
+    size: WindowSize,
+    file: BufWriter<File>,
+    started: Instant,
+    written: usize,
+    /// Output not yet written because it ends in the middle of a UTF-8 sequence.
+    partial: Vec<u8>,
+    /// Whether the last byte of output written was a carriage return.
+    after_cr: bool,
+    /// The client that sent the last recorded input.
+    last_input: Option<ClientRef>,
+}
+
+impl Writer {
+    fn create(config: RecordConfig, framing: Framing, header: Header, size: WindowSize) -> io::Result<Self> {
+        // keep the recording of an earlier session, like a finished one
+        if config.path.exists() {
+            fs::rename(&config.path, rotate::numbered_path(&config.path))?;
+        }
+
+        let file = BufWriter::new(File::create(&config.path)?);
+
+        let mut writer = Writer {
+            config,
+            framing,
+            header,
+            size,
+            file,
+            started: Instant::now(),
+            written: 0,
+            partial: Vec::new(),
+            after_cr: false,
+            last_input: None,
+        };
+
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_record(&mut self, time: Instant, record: Record) -> io::Result<()> {
+        if self.config.rotate.is_due(self.written, self.started) {
+            self.roll_over()?;
+        }
+
+        match record {
+            Record::Output(data) => {
+                let data = self.terminal_output(&data);
+                self.partial.extend_from_slice(&data);
+
+                let text = take_utf8(&mut self.partial);
+                if !text.is_empty() {
+                    self.write_event(time, "o", &text)?;
+                }
+            }
+            Record::Input(from, data) => {
+                if self.last_input != Some(from) {
+                    self.last_input = Some(from);
+                    self.write_event(time, "m", &format!("input from {}", from))?;
+                }
+
+                let data = sink::encode(self.framing, &data);
+                self.write_event(time, "i", &String::from_utf8_lossy(&data))?;
+            }
+            Record::Resize(size) => {
+                self.size = size;
+                self.write_event(time, "r", &format!("{}x{}", size.cols, size.rows))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the current recording out of the way and start a new one.
+    fn roll_over(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        fs::rename(&self.config.path, rotate::numbered_path(&self.config.path))?;
+
+        self.file = BufWriter::new(File::create(&self.config.path)?);
+        self.started = Instant::now();
+        self.written = 0;
+        self.last_input = None;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        let mut line = format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"command\": ", self.size.cols, self.size.rows, timestamp);
+        push_json_str(&mut line, &self.header.command);
+
+        if let Some(term) = &self.header.term {
+            line.push_str(", \"env\": {\"TERM\": ");
+            push_json_str(&mut line, term);
+            line.push('}');
+        }
+
+        line.push_str("}\n");
+        self.write_line(&line)
+    }
+
+    fn write_event(&mut self, time: Instant, kind: &str, data: &str) -> io::Result<()> {
+        // events may have been sent before the current recording started
+        let offset = time.saturating_duration_since(self.started).as_secs_f64();
+
+        let mut line = format!("[{:.6}, \"{}\", ", offset, kind);
+        push_json_str(&mut line, data);
+        line.push_str("]\n");
+        self.write_line(&line)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len();
+        Ok(())
+    }
+
+    /// Turn output into what a terminal shows, where every line feed also returns to the start
+    /// of the line.
+    fn terminal_output(&mut self, data: &[u8]) -> Vec<u8> {
+        let data = sink::encode(self.framing, data);
+        let mut result = Vec::with_capacity(data.len());
+
+        for &b in &data[..] {
+            if b == b'\n' && !self.after_cr {
+                result.push(b'\r');
+            }
+
+            result.push(b);
+            self.after_cr = b == b'\r';
+        }
+
+        result
+    }
+}
+
+impl Sink for Writer {
+    type Record = (Instant, Record);
+
+    fn describe(&self) -> String {
+        format!("recording to {}", self.config.path.display())
+    }
+
+    fn write(&mut self, (time, record): Self::Record) -> io::Result<()> {
+        self.write_record(time, record)
+    }
+
+    fn dropped(&mut self, count: usize) -> io::Result<()> {
+        self.write_event(Instant::now(), "m", &format!("{} records dropped", count))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Take the text from the start of the buffer, leaving a UTF-8 sequence that is cut off at the
+/// end for later. Bytes that aren't valid UTF-8 are replaced.
+fn take_utf8(buf: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut rest = &buf[..];
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                text.push_str(s);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let rest = rest.len();
+    buf.drain(..buf.len() - rest);
+    text
+}
+
+/// Append a string to JSON being written, quoted and escaped.
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_str(s: &str) -> String {
+        let mut out = String::new();
+        push_json_str(&mut out, s);
+        out
+    }
+
+    #[test]
+    fn take_complete_text() {
+        let mut buf = "héllo".as_bytes().to_vec();
+        assert_eq!(take_utf8(&mut buf), "héllo");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn take_cut_off_sequence() {
+        let euro = "€".as_bytes();
+        let mut buf = b"a".to_vec();
+        buf.extend_from_slice(&euro[..2]);
+
+        assert_eq!(take_utf8(&mut buf), "a");
+        assert_eq!(buf, &euro[..2]);
+
+        buf.extend_from_slice(&euro[2..]);
+        buf.push(b'b');
+        assert_eq!(take_utf8(&mut buf), "€b");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn take_invalid_bytes() {
+        let mut buf = b"a\xffb\xe2\x82c".to_vec();
+        assert_eq!(take_utf8(&mut buf), "a\u{fffd}b\u{fffd}c");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_str(""), r#""""#);
+        assert_eq!(json_str("plain €"), r#""plain €""#);
+        assert_eq!(json_str("\"quoted\" \\"), r#""\"quoted\" \\""#);
+        assert_eq!(json_str("a\r\n\tb"), r#""a\r\n\tb""#);
+        assert_eq!(json_str("\x1b[0m\x00\x7f"), r#""\u001b[0m\u0000\u007f""#);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// When to move a file that is being written continuously out of the way and start a new one.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RotateConfig {
+    /// Start a new file once the current one is this large, in bytes.
+    pub max_size: Option<usize>,
+    /// Start a new file once the current one has been written to for this long.
+    pub max_time: Option<Duration>,
+}
+
+impl RotateConfig {
+    /// Whether a file that was started at the given time and has had the given number of bytes
+    /// written to it should be replaced.
+    pub fn is_due(&self, written: usize, started: Instant) -> bool {
+        self.max_size.is_some_and(|max| written >= max)
+            || self.max_time.is_some_and(|max| started.elapsed() >= max)
+    }
+}
+
+/// Find the first unused name for a finished file, numbering them in the order they were
+/// finished, like `session.1.cast` for `session.cast`. A number is also taken if a compressed
+/// file with it exists.
+pub fn numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let ext = path.extension().map(|e| e.to_string_lossy().into_owned());
+
+    (1..)
+        .map(|n| {
+            let name = match &ext {
+                Some(ext) => format!("{}.{}.{}", stem, n, ext),
+                None => format!("{}.{}", stem, n),
+            };
+
+            path.with_file_name(name)
+        })
+        .find(|p| !p.exists() && !compressed_path(p).exists())
+        .unwrap()
+}
+
+/// The name of the compressed version of a file.
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn numbered_paths() {
+        let dir = std::env::temp_dir().join(format!("conlink-rotate-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("session.cast");
+        assert_eq!(numbered_path(&path), dir.join("session.1.cast"));
+
+        fs::write(dir.join("session.1.cast"), "").unwrap();
+        fs::write(dir.join("session.2.cast.gz"), "").unwrap();
+        assert_eq!(numbered_path(&path), dir.join("session.3.cast"));
+
+        assert_eq!(numbered_path(&dir.join("output")), dir.join("output.1"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_paths() {
+        assert_eq!(compressed_path(Path::new("/var/log/app.1.log")), Path::new("/var/log/app.1.log.gz"));
+    }
+
+    #[test]
+    fn due() {
+        let started = Instant::now();
+        assert!(!RotateConfig::default().is_due(usize::MAX, started));
+
+        let by_size = RotateConfig { max_size: Some(100), max_time: None };
+        assert!(!by_size.is_due(99, started));
+        assert!(by_size.is_due(100, started));
+
+        let by_time = RotateConfig { max_size: None, max_time: Some(Duration::from_secs(60)) };
+        assert!(!by_time.is_due(0, started));
+        assert!(by_time.is_due(0, started - Duration::from_secs(60)));
+    }
+}
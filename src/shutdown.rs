@@ -0,0 +1,54 @@
+use std::io;
+
+use futures::future;
+use libc::c_int;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::stream::StreamExt;
+use tokio::sync::watch;
+
+/// Tells the parts of conlink that it has been asked to exit, and by which signal.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<Option<c_int>>,
+}
+
+impl Shutdown {
+    /// Start a task waiting for SIGINT or SIGTERM.
+    pub fn on_signals() -> io::Result<Self> {
+        let interrupt = signal(SignalKind::interrupt())?.map(|_| libc::SIGINT);
+        let terminate = signal(SignalKind::terminate())?.map(|_| libc::SIGTERM);
+        let (tx, requested) = watch::channel(None);
+
+        tokio::spawn(async move {
+            let mut signals = interrupt.merge(terminate);
+
+            if let Some(signal) = signals.next().await {
+                eprintln!("received signal {}, shutting down", signal);
+                let _ = tx.broadcast(Some(signal));
+            }
+
+            // keep the receivers from seeing the channel closed
+            future::pending::<()>().await;
+        });
+
+        Ok(Shutdown { requested })
+    }
+
+    /// The signal conlink was asked to exit with, if it has been.
+    pub fn requested(&self) -> Option<c_int> {
+        *self.requested.borrow()
+    }
+
+    /// Wait until conlink is asked to exit, returning the signal it received.
+    pub async fn wait(&mut self) -> c_int {
+        loop {
+            if let Some(signal) = self.requested() {
+                return signal;
+            }
+
+            if self.requested.recv().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
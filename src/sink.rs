@@ -0,0 +1,137 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use bytes::BytesMut;
+use tokio::sync::oneshot;
+use tokio_util::codec::Encoder;
+
+use crate::framing::{FrameCodec, Framing};
+
+/// The most records waiting to be written before further ones are dropped.
+const QUEUE_LIMIT: usize = 4096;
+
+/// Something written to a file by a background thread, so that the program's output isn't held
+/// up by the disk.
+pub trait Sink: Send + 'static {
+    type Record: Send + 'static;
+
+    /// What is being written, for error messages.
+    fn describe(&self) -> String;
+
+    fn write(&mut self, record: Self::Record) -> io::Result<()>;
+
+    /// Note that records were dropped because the disk couldn't keep up.
+    fn dropped(&mut self, count: usize) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+enum Message<R> {
+    Record(R),
+    /// Write out everything sent so far, and say so.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Sends records to a sink running on its own thread.
+pub struct Background<R> {
+    tx: SyncSender<Message<R>>,
+    /// The number of records dropped since the sink last heard about it.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl<R> Clone for Background<R> {
+    fn clone(&self) -> Self {
+        Background {
+            tx: self.tx.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<R: Send + 'static> Background<R> {
+    /// Start a thread with the given name writing to the sink.
+    pub fn start<S: Sink<Record = R>>(name: &str, sink: S) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LIMIT);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let counter = dropped.clone();
+
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || run(sink, rx, &counter))?;
+
+        Ok(Background { tx, dropped })
+    }
+
+    /// Send a record, dropping it if too many are waiting to be written.
+    pub fn send(&self, record: R) {
+        // if the sink has stopped, it has already said why
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Record(record)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Send a record that must not be dropped, waiting for room if necessary.
+    pub async fn send_waiting(&self, record: R) {
+        self.send_blocking(Message::Record(record)).await
+    }
+
+    /// Wait until everything sent so far has been written out.
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send_blocking(Message::Flush(tx)).await;
+        let _ = rx.await;
+    }
+
+    /// Send a message, waiting for room on a thread that may block, so that the runtime isn't held
+    /// up while the sink catches up.
+    async fn send_blocking(&self, message: Message<R>) {
+        let tx = self.tx.clone();
+        let _ = tokio::task::spawn_blocking(move || tx.send(message)).await;
+    }
+}
+
+fn run<S: Sink>(mut sink: S, rx: Receiver<Message<S::Record>>, dropped: &AtomicUsize) {
+    while let Ok(message) = rx.recv() {
+        let mut result = write(&mut sink, message, dropped);
+
+        // write out everything that arrived together before flushing
+        while result.is_ok() {
+            match rx.try_recv() {
+                Ok(message) => result = write(&mut sink, message, dropped),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = result.and_then(|_| sink.flush()) {
+            eprintln!("failed to write {}, stopping: {}", sink.describe(), e);
+            return;
+        }
+    }
+}
+
+fn write<S: Sink>(sink: &mut S, message: Message<S::Record>, dropped: &AtomicUsize) -> io::Result<()> {
+    match dropped.swap(0, Ordering::Relaxed) {
+        0 => {}
+        count => sink.dropped(count)?,
+    }
+
+    match message {
+        Message::Record(record) => sink.write(record),
+        Message::Flush(done) => {
+            sink.flush()?;
+            let _ = done.send(());
+            Ok(())
+        }
+    }
+}
+
+/// Put a message back into the form it had in the stream it was read from.
+pub fn encode(framing: Framing, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    // messages that were read in this framing can be written in it
+    let _ = FrameCodec::new(framing).encode(data, &mut buf);
+    buf
+}
@@ -0,0 +1,381 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use tokio::process::Child;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::client::broadcast::{Audience, Broadcast};
+use crate::client::Identity;
+use crate::client::event::Event;
+use crate::client::shared::Shared;
+use crate::cmd::{self, StderrLabel};
+use crate::pty::PtyConfig;
+use crate::shutdown::Shutdown;
+
+/// The delay before the first restart, doubled for each restart after it.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay before a restart. A program that ran at least this long before exiting is
+/// restarted after the initial delay again.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait for the rest of the output after the program has exited, in case something
+/// else it started keeps its output open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the program to exit when stopping it, before killing it.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When to start the program again after it exits.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum RestartPolicy {
+    Never,
+    Always,
+
+    /// Only restart the program if it exits with a non-zero status or because of a signal.
+    OnFailure,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "always" => Ok(RestartPolicy::Always),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            _ => Err(format!("invalid restart policy '{}', expected never, always or on-failure", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    /// The maximum number of restarts, if limited.
+    pub limit: Option<u32>,
+}
+
+impl RestartConfig {
+    fn applies(&self, status: ExitStatus) -> bool {
+        match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !status.success(),
+        }
+    }
+}
+
+/// Runs the program, connecting it to the clients, and starts it again when it exits if
+/// configured to.
+pub struct Supervisor {
+    command: Vec<String>,
+    pty: Option<PtyConfig>,
+    label: StderrLabel,
+    restart: RestartConfig,
+    /// If set, the program is only run while clients are connected, and stopped once none have
+    /// been for this long.
+    on_demand: Option<Duration>,
+    /// The client the program is run for, if it is run separately for each client.
+    owner: Option<Identity>,
+    state: Arc<Mutex<Shared>>,
+    shutdown: Shutdown,
+}
+
+/// How a run of the program ended.
+enum Ended {
+    /// The program exited and wasn't restarted.
+    Exited(ExitStatus),
+
+    /// The program was stopped because no clients were connected.
+    Idle(ExitStatus),
+
+    /// The program was stopped because conlink is exiting.
+    Stopped(ExitStatus),
+}
+
+/// A started instance of the program.
+struct Running {
+    child: Child,
+    /// The task passing on the program's output.
+    output: JoinHandle<()>,
+    started: Instant,
+}
+
+impl Supervisor {
+    pub fn new(command: &[String], pty: Option<PtyConfig>, label: StderrLabel, restart: RestartConfig, on_demand: Option<Duration>, state: Arc<Mutex<Shared>>, shutdown: Shutdown) -> Self {
+        Supervisor {
+            command: command.to_vec(),
+            pty,
+            label,
+            restart,
+            on_demand,
+            owner: None,
+            state,
+            shutdown,
+        }
+    }
+
+    /// Run the program for a single client, logging when it starts and exits.
+    pub fn for_client(mut self, identity: Identity) -> Self {
+        self.owner = Some(identity);
+        self
+    }
+
+    /// Start the program and pass client input to it.
+    async fn launch(&self) -> io::Result<Running> {
+        let (child, io) = cmd::start_command(&self.command, self.pty.as_ref())?;
+
+        if let Some(owner) = &self.owner {
+            eprintln!("started process {} for {}", child.id(), owner);
+        }
+
+        let mut state = self.state.lock().await;
+        state.attach(io.stdin, child.id(), io.terminal);
+        let output = cmd::process_output(io.stdout, io.stderr, state.output().clone(), state.framing(), self.label.clone(), state.recorder(), state.log());
+
+        Ok(Running { child, output, started: Instant::now() })
+    }
+
+    /// Run the program until it exits and isn't restarted anymore, or conlink is asked to exit,
+    /// returning its last exit status. When only running the program while clients are
+    /// connected, this only returns once conlink is asked to exit, without a status if the
+    /// program wasn't running then.
+    pub async fn run(self) -> io::Result<Option<ExitStatus>> {
+        let mut presence = self.state.lock().await.presence();
+
+        loop {
+            if self.on_demand.is_some() && !self.wait_for_start(&mut presence).await {
+                return Ok(None);
+            }
+
+            match self.run_instance(&mut presence).await? {
+                Ended::Exited(status) if self.on_demand.is_none() => return Ok(Some(status)),
+                Ended::Stopped(status) => return Ok(Some(status)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Run the program once, restarting it as configured, until it exits or is stopped because
+    /// no clients are connected anymore or conlink is exiting. Returns its last exit status.
+    pub async fn run_once(self) -> io::Result<ExitStatus> {
+        let mut presence = self.state.lock().await.presence();
+
+        match self.run_instance(&mut presence).await? {
+            Ended::Exited(status) | Ended::Idle(status) | Ended::Stopped(status) => Ok(status),
+        }
+    }
+
+    /// Run the program, restarting it as configured, until it exits and isn't restarted anymore
+    /// or is stopped because no clients are connected or conlink is exiting.
+    async fn run_instance(&self, presence: &mut watch::Receiver<usize>) -> io::Result<Ended> {
+        let mut shutdown = self.shutdown.clone();
+        let mut running = self.launch().await?;
+        let mut restarts = 0;
+        let mut delay = INITIAL_DELAY;
+
+        loop {
+            let status = tokio::select! {
+                status = &mut running.child => status?,
+                _ = wait_idle(presence, self.on_demand.unwrap_or_default()), if self.on_demand.is_some() => {
+                    eprintln!("stopping process, no clients are connected");
+                    let status = self.stop(&mut running.child, self.hangup_signal()).await?;
+                    self.finish(running, status, false).await;
+                    return Ok(Ended::Idle(status));
+                }
+                signal = shutdown.wait() => {
+                    eprintln!("passing signal {} on to process {}", signal, running.child.id());
+                    let status = self.stop(&mut running.child, signal).await?;
+                    self.finish(running, status, false).await;
+                    return Ok(Ended::Stopped(status));
+                }
+            };
+
+            let limited = self.restart.limit.is_some_and(|limit| restarts >= limit);
+            let restart = self.restart.applies(status) && !limited;
+            let started = running.started;
+            let output = self.finish(running, status, restart).await;
+
+            if !restart {
+                if self.restart.applies(status) {
+                    let msg = format!("process exited with {}, not restarting it after {} restarts", describe(status), restarts);
+                    eprintln!("{}", msg);
+                    output.send(Event::Notice(msg), Audience::All).await;
+                }
+
+                return Ok(Ended::Exited(status));
+            }
+
+            if started.elapsed() >= MAX_DELAY {
+                delay = INITIAL_DELAY;
+            }
+
+            let msg = format!("process exited with {}, restarting in {}s", describe(status), delay.as_secs());
+            eprintln!("{}", msg);
+            output.send(Event::Notice(msg), Audience::All).await;
+
+            tokio::select! {
+                _ = tokio::time::delay_for(delay) => {}
+                _ = shutdown.wait() => return Ok(Ended::Stopped(status)),
+            }
+
+            delay = (delay * 2).min(MAX_DELAY);
+
+            running = self.launch().await?;
+            restarts += 1;
+
+            output.send(Event::Notice(format!("process restarted after exiting with {}", describe(status))), Audience::All).await;
+        }
+    }
+
+    /// Wait until the program should be started because a client has connected. Returns false if
+    /// conlink is asked to exit instead.
+    async fn wait_for_start(&self, presence: &mut watch::Receiver<usize>) -> bool {
+        let mut shutdown = self.shutdown.clone();
+
+        while !self.state.lock().await.wants_start() {
+            tokio::select! {
+                changed = presence.recv() => if changed.is_none() { break; },
+                _ = shutdown.wait() => return false,
+            }
+        }
+
+        true
+    }
+
+    /// The signal asking the program to exit when its clients are gone.
+    fn hangup_signal(&self) -> c_int {
+        // a program on a terminal expects a hangup when the terminal goes away
+        if self.pty.is_some() { libc::SIGHUP } else { libc::SIGTERM }
+    }
+
+    /// Ask the program to exit with a signal, killing it if it doesn't.
+    async fn stop(&self, child: &mut Child, signal: c_int) -> io::Result<ExitStatus> {
+        unsafe { libc::kill(child.id() as libc::pid_t, signal); }
+
+        match tokio::time::timeout(STOP_TIMEOUT, &mut *child).await {
+            Ok(status) => status,
+            Err(_) => {
+                child.kill()?;
+                child.await
+            }
+        }
+    }
+
+    /// Clean up after the program has exited and tell clients about it. Returns the output to
+    /// send further messages to.
+    async fn finish(&self, running: Running, status: ExitStatus, restarting: bool) -> Arc<Broadcast<Event>> {
+        if let Some(owner) = &self.owner {
+            let secs = running.started.elapsed().as_secs_f32();
+            eprintln!("process {} for {} exited with {} after {:.1}s", running.child.id(), owner, describe(status), secs);
+        }
+
+        // closing the input lets anything the program left behind that still reads it exit too
+        let output = {
+            let mut state = self.state.lock().await;
+            state.detach(restarting);
+            state.output().clone()
+        };
+
+        // pass on what the program wrote before exiting before telling clients it exited
+        let _ = tokio::time::timeout(DRAIN_TIMEOUT, running.output).await;
+
+        output.send(Event::Exit(status), Audience::All).await;
+        output
+    }
+}
+
+/// Wait until no clients have been connected for the grace period.
+async fn wait_idle(presence: &mut watch::Receiver<usize>, grace: Duration) {
+    loop {
+        while *presence.borrow() > 0 {
+            if presence.recv().await.is_none() {
+                return;
+            }
+        }
+
+        let reconnect = async {
+            while *presence.borrow() == 0 {
+                if presence.recv().await.is_none() {
+                    return;
+                }
+            }
+        };
+
+        if tokio::time::timeout(grace, reconnect).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Describe how the program exited, for messages to clients.
+pub fn describe(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("status {}", code),
+        (None, Some(signal)) => format!("signal {}", signal),
+        (None, None) => "unknown status".to_owned(),
+    }
+}
+
+/// The exit code for conlink to exit with after the program exited, which like in a shell is 128
+/// plus the signal if the program was killed by one.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 126,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn killed(signal: c_int) -> ExitStatus {
+        ExitStatus::from_raw(signal)
+    }
+
+    #[test]
+    fn parse_restart_policies() {
+        assert_eq!("never".parse(), Ok(RestartPolicy::Never));
+        assert_eq!("always".parse(), Ok(RestartPolicy::Always));
+        assert_eq!("on-failure".parse(), Ok(RestartPolicy::OnFailure));
+        assert!("sometimes".parse::<RestartPolicy>().is_err());
+    }
+
+    #[test]
+    fn restart_policies() {
+        let config = |policy| RestartConfig { policy, limit: None };
+
+        assert!(!config(RestartPolicy::Never).applies(exited(1)));
+        assert!(config(RestartPolicy::Always).applies(exited(0)));
+        assert!(!config(RestartPolicy::OnFailure).applies(exited(0)));
+        assert!(config(RestartPolicy::OnFailure).applies(exited(1)));
+        assert!(config(RestartPolicy::OnFailure).applies(killed(libc::SIGKILL)));
+    }
+
+    #[test]
+    fn describe_status() {
+        assert_eq!(describe(exited(3)), "status 3");
+        assert_eq!(describe(killed(libc::SIGTERM)), format!("signal {}", libc::SIGTERM));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(exited(0)), 0);
+        assert_eq!(exit_code(exited(42)), 42);
+        assert_eq!(exit_code(killed(libc::SIGINT)), 130);
+        assert_eq!(exit_code(killed(libc::SIGKILL)), 137);
+    }
+}
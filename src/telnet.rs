@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::io;
+
+use futures::task::Context;
+use tokio::io::AsyncWriteExt;
+use tokio::macros::support::{Pin, Poll};
+use tokio::prelude::{AsyncRead, AsyncWrite};
+
+use crate::client::Connection;
+use crate::pty::WindowSize;
+
+// commands
+const SE: u8 = 240;
+const BRK: u8 = 243;
+const IP: u8 = 244;
+const AYT: u8 = 246;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// options
+const ECHO: u8 = 1;
+const SGA: u8 = 3;
+const NAWS: u8 = 31;
+
+/// The most replies kept for a client that doesn't read them, beyond which more are dropped.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// The longest subnegotiation accepted, which is much longer than any supported one.
+const MAX_SUBNEGOTIATION: usize = 64;
+
+/// Sent in reply to an "are you there" command.
+const AYT_REPLY: &[u8] = b"\r\n[conlink: yes]\r\n";
+
+/// Something the client asked for using a telnet command.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TelnetEvent {
+    /// The user wants to interrupt the program, like by pressing Ctrl-C.
+    Interrupt,
+
+    /// The size of the client's terminal.
+    WindowSize(WindowSize),
+}
+
+/// Where the parser is in the incoming data.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum State {
+    Data,
+    /// After a carriage return, which is followed by a line feed or a null byte.
+    Cr,
+    Iac,
+    /// After a command that takes an option.
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// A connection speaking the telnet protocol. Commands are taken out of the data read from the
+/// client and answered, and data written to the client is converted to telnet's line endings.
+///
+/// In character mode, the client is asked to send every key right away and leave echoing them
+/// to conlink, which suits programs running on a pseudo-terminal. Otherwise, the client edits
+/// lines itself and sends them when complete.
+pub struct TelnetStream {
+    inner: Box<dyn Connection>,
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Data from the client with the commands taken out, not yet read.
+    decoded: Vec<u8>,
+    /// Options enabled on conlink's side.
+    local: [bool; 256],
+    /// Options enabled on the client's side.
+    remote: [bool; 256],
+    character_mode: bool,
+    /// Data to write to the client before anything else.
+    pending: Vec<u8>,
+    /// Whether the last byte written was a carriage return.
+    after_cr: bool,
+    events: VecDeque<TelnetEvent>,
+}
+
+impl TelnetStream {
+    /// Start negotiating options with a newly connected client.
+    pub async fn start(inner: Box<dyn Connection>, character_mode: bool) -> io::Result<Self> {
+        let mut stream = TelnetStream {
+            inner,
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            decoded: Vec::new(),
+            local: [false; 256],
+            remote: [false; 256],
+            character_mode,
+            pending: Vec::new(),
+            after_cr: false,
+            events: VecDeque::new(),
+        };
+
+        stream.remote[NAWS as usize] = true;
+        stream.pending.extend_from_slice(&[IAC, DO, NAWS]);
+
+        if character_mode {
+            stream.local[ECHO as usize] = true;
+            stream.local[SGA as usize] = true;
+            stream.pending.extend_from_slice(&[IAC, WILL, ECHO, IAC, WILL, SGA]);
+        }
+
+        stream.flush().await?;
+        Ok(stream)
+    }
+
+    /// Take the next event the client sent.
+    pub fn next_event(&mut self) -> Option<TelnetEvent> {
+        self.events.pop_front()
+    }
+
+    fn supports_local(&self, option: u8) -> bool {
+        match option {
+            ECHO | SGA => self.character_mode,
+            _ => false,
+        }
+    }
+
+    fn reply(&mut self, data: &[u8]) {
+        if self.pending.len() < MAX_PENDING {
+            self.pending.extend_from_slice(data);
+        }
+    }
+
+    /// Take the commands out of data read from the client and handle them, keeping the rest.
+    fn parse(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state = self.step(self.state, b);
+        }
+    }
+
+    fn step(&mut self, state: State, b: u8) -> State {
+        match (state, b) {
+            (State::Data, IAC) => State::Iac,
+            (State::Data, b'\r') => State::Cr,
+            (State::Data, b) => {
+                self.decoded.push(b);
+                State::Data
+            }
+            // a line ends with CR LF, and a carriage return on its own is sent as CR NUL
+            (State::Cr, b'\n') => {
+                self.decoded.push(b'\n');
+                State::Data
+            }
+            (State::Cr, 0) => {
+                self.decoded.push(b'\r');
+                State::Data
+            }
+            (State::Cr, b) => {
+                self.decoded.push(b'\r');
+                self.step(State::Data, b)
+            }
+            (State::Iac, IAC) => {
+                self.decoded.push(IAC);
+                State::Data
+            }
+            (State::Iac, WILL) | (State::Iac, WONT) | (State::Iac, DO) | (State::Iac, DONT) => State::Option(b),
+            (State::Iac, SB) => {
+                self.subnegotiation.clear();
+                State::Subnegotiation
+            }
+            (State::Iac, IP) | (State::Iac, BRK) => {
+                self.events.push_back(TelnetEvent::Interrupt);
+                State::Data
+            }
+            (State::Iac, AYT) => {
+                self.reply(AYT_REPLY);
+                State::Data
+            }
+            // other commands don't mean anything here
+            (State::Iac, _) => State::Data,
+            (State::Option(command), option) => {
+                self.negotiate(command, option);
+                State::Data
+            }
+            (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+            (State::Subnegotiation, b) | (State::SubnegotiationIac, b @ IAC) => {
+                if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                    self.subnegotiation.push(b);
+                }
+
+                State::Subnegotiation
+            }
+            (State::SubnegotiationIac, SE) => {
+                self.subnegotiated();
+                State::Data
+            }
+            (State::SubnegotiationIac, _) => State::Subnegotiation,
+        }
+    }
+
+    /// Answer a request to enable or disable an option. Requests that don't change anything aren't
+    /// answered, so that the client and conlink don't keep answering each other.
+    fn negotiate(&mut self, command: u8, option: u8) {
+        let i = option as usize;
+
+        match command {
+            DO if self.supports_local(option) => {
+                if !self.local[i] {
+                    self.local[i] = true;
+                    self.reply(&[IAC, WILL, option]);
+                }
+            }
+            DO => self.reply(&[IAC, WONT, option]),
+            DONT => self.local[i] = false,
+            WILL if option == NAWS => {
+                if !self.remote[i] {
+                    self.remote[i] = true;
+                    self.reply(&[IAC, DO, option]);
+                }
+            }
+            WILL => self.reply(&[IAC, DONT, option]),
+            _ => self.remote[i] = false,
+        }
+    }
+
+    fn subnegotiated(&mut self) {
+        if let [NAWS, c1, c0, r1, r0] = self.subnegotiation[..] {
+            let size = WindowSize {
+                cols: u16::from_be_bytes([c1, c0]),
+                rows: u16::from_be_bytes([r1, r0]),
+            };
+
+            // a size of 0 means the client doesn't know it
+            if size.cols > 0 && size.rows > 0 {
+                self.events.push_back(TelnetEvent::WindowSize(size));
+            }
+        }
+    }
+
+    /// Write out as much of the pending data as possible.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.pending.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for TelnetStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.decoded.is_empty() {
+                let len = buf.len().min(self.decoded.len());
+                buf[..len].copy_from_slice(&self.decoded[..len]);
+                self.decoded.drain(..len);
+                return Poll::Ready(Ok(len));
+            }
+
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+
+            self.parse(&buf[..n]);
+
+            // send answers right away if possible, otherwise with the next output
+            if let Poll::Ready(Err(e)) = self.poll_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TelnetStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_pending(cx))?;
+
+        for &b in buf {
+            match b {
+                IAC => self.pending.extend_from_slice(&[IAC, IAC]),
+                b'\n' if !self.after_cr => self.pending.extend_from_slice(b"\r\n"),
+                b => self.pending.push(b),
+            }
+
+            self.after_cr = b == b'\r';
+        }
+
+        // the data has been taken, even if it can't be written yet
+        if let Poll::Ready(Err(e)) = self.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    async fn start(character_mode: bool) -> (TelnetStream, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let stream = TelnetStream::start(Box::new(ours), character_mode).await.unwrap();
+        (stream, theirs)
+    }
+
+    #[tokio::test]
+    async fn start_negotiation() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let _stream = TelnetStream::start(Box::new(ours), true).await.unwrap();
+
+        let mut buf = [0; 9];
+        theirs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [IAC, DO, NAWS, IAC, WILL, ECHO, IAC, WILL, SGA]);
+    }
+
+    #[tokio::test]
+    async fn line_endings() {
+        let (mut stream, _theirs) = start(true).await;
+        stream.parse(b"one\r\ntwo\r\0three\rfour\r");
+        assert_eq!(stream.decoded, b"one\ntwo\rthree\rfour");
+        assert_eq!(stream.state, State::Cr);
+
+        // the rest of a line ending can arrive separately
+        stream.parse(b"\0");
+        assert_eq!(stream.decoded, b"one\ntwo\rthree\rfour\r");
+    }
+
+    #[tokio::test]
+    async fn escaped_iac() {
+        let (mut stream, _theirs) = start(false).await;
+        stream.parse(&[b'a', IAC, IAC, b'b', IAC]);
+        stream.parse(&[IAC]);
+        assert_eq!(stream.decoded, [b'a', IAC, b'b', IAC]);
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let (mut stream, _theirs) = start(false).await;
+        stream.parse(&[b'a', IAC, IP, b'b', IAC, AYT, IAC, 241, b'c']);
+
+        assert_eq!(stream.decoded, b"abc");
+        assert_eq!(stream.next_event(), Some(TelnetEvent::Interrupt));
+        assert_eq!(stream.next_event(), None);
+        assert_eq!(stream.pending, AYT_REPLY);
+    }
+
+    #[tokio::test]
+    async fn window_size() {
+        let (mut stream, _theirs) = start(true).await;
+        stream.parse(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+        stream.parse(&[IAC, SB, NAWS, 1, IAC, IAC, 0, 50, IAC]);
+        stream.parse(&[SE]);
+        // a size of 0 is ignored
+        stream.parse(&[IAC, SB, NAWS, 0, 0, 0, 0, IAC, SE]);
+
+        assert_eq!(stream.next_event(), Some(TelnetEvent::WindowSize(WindowSize { cols: 80, rows: 24 })));
+        assert_eq!(stream.next_event(), Some(TelnetEvent::WindowSize(WindowSize { cols: 511, rows: 50 })));
+        assert_eq!(stream.next_event(), None);
+        assert!(stream.decoded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn negotiate_options() {
+        let (mut stream, _theirs) = start(false).await;
+
+        // only NAWS is accepted from the client, and nothing is offered outside character mode
+        stream.parse(&[IAC, WILL, NAWS, IAC, WILL, ECHO, IAC, DO, ECHO]);
+        assert_eq!(stream.pending, [IAC, DONT, ECHO, IAC, WONT, ECHO]);
+        assert!(stream.decoded.is_empty());
+
+        let (mut stream, _theirs) = start(true).await;
+
+        // options already enabled aren't confirmed again
+        stream.parse(&[IAC, DO, ECHO, IAC, DONT, SGA, IAC, DO, SGA]);
+        assert_eq!(stream.pending, [IAC, WILL, SGA]);
+    }
+
+    #[tokio::test]
+    async fn write() {
+        let (mut stream, mut theirs) = start(false).await;
+        stream.write_all(&[b'a', b'\n', b'b', b'\r', b'\n', IAC]).await.unwrap();
+        drop(stream);
+
+        let mut data = Vec::new();
+        theirs.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data[..3], [IAC, DO, NAWS]);
+        assert_eq!(data[3..], [b'a', b'\r', b'\n', b'b', b'\r', b'\n', IAC, IAC]);
+    }
+}
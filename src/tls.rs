@@ -0,0 +1,73 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::PrivateKey;
+use tokio_rustls::TlsAcceptor;
+
+/// Settings for accepting TLS connections.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The PEM file containing the server certificate chain.
+    pub cert: PathBuf,
+
+    /// The PEM file containing the server's private key.
+    pub key: PathBuf,
+
+    /// If set, clients must present a certificate signed by one of the CAs in this PEM file.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn create_acceptor(&self) -> io::Result<TlsAcceptor> {
+        let verifier = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                let (valid, _) = roots.add_pem_file(&mut open(path)?)
+                    .map_err(|_| invalid_data(path, "failed to read CA certificates"))?;
+
+                if valid == 0 {
+                    return Err(invalid_data(path, "no valid CA certificates found"));
+                }
+
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let certs = pemfile::certs(&mut open(&self.cert)?)
+            .map_err(|_| invalid_data(&self.cert, "failed to read certificates"))?;
+
+        let key = read_key(&self.key)?;
+
+        let mut config = ServerConfig::new(verifier);
+        config.set_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| invalid_data(path, "failed to read private key"))?;
+
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?)
+            .map_err(|_| invalid_data(path, "failed to read private key"))?;
+    }
+
+    keys.into_iter().next().ok_or_else(|| invalid_data(path, "no private key found"))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+fn invalid_data(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}